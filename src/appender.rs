@@ -0,0 +1,242 @@
+// appender.rs
+
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, Record};
+use log4rs::append::Append;
+use simple_error::bail;
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "mt-recorder";
+
+// systemd-journald native protocol
+//
+// each record is one datagram of FIELD=value lines, the structured
+// key-values become upper case fields e.g. connection ⇒ CONNECTION
+#[derive(Debug)]
+pub struct Journald {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl Journald {
+    pub fn new() -> MyResult<Self> {
+        Ok(Journald {
+            socket: UnixDatagram::unbound()?,
+            path: PathBuf::from(JOURNALD_SOCKET),
+        })
+    }
+}
+
+impl Append for Journald {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        journal_field(&mut buf, "MESSAGE", &record.args().to_string());
+        journal_field(&mut buf, "PRIORITY", &priority(record.level()).to_string());
+        journal_field(&mut buf, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        journal_field(&mut buf, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            journal_field(&mut buf, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            journal_field(&mut buf, "CODE_LINE", &line.to_string());
+        }
+        // a module path, CODE_FUNC is for the function name
+        if let Some(module) = record.module_path() {
+            journal_field(&mut buf, "RUST_MODULE", module);
+        }
+        record
+            .key_values()
+            .visit(&mut Fields(&mut buf))
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+        self.socket.send_to(&buf, &self.path)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        journal_field(self.0, &journal_name(key.as_str()), &value.to_string());
+        Ok(())
+    }
+}
+
+// field names are upper case letters, digits and underscore and must
+// not start with a digit or underscore
+fn journal_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    match name.chars().next() {
+        Some('A'..='Z') => name,
+        _ => format!("F{}", name),
+    }
+}
+
+// values containing a newline use the binary length prefixed form
+fn journal_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+#[derive(Debug)]
+enum Transport {
+    Unix(UnixDatagram, PathBuf),
+    Udp(UdpSocket),
+}
+
+// syslog to the local socket (BSD format) or to a relay over UDP (RFC 5424)
+#[derive(Debug)]
+pub struct Syslog {
+    transport: Transport,
+    facility: u8,
+    hostname: String,
+    pid: u32,
+}
+
+impl Syslog {
+    // address: "unix:/dev/log", "/dev/log" or "udp:127.0.0.1:514"
+    pub fn new(address: &str, facility: &str) -> MyResult<Self> {
+        let transport = if let Some(host) = address.strip_prefix("udp:") {
+            let local = if host.starts_with('[') {
+                "[::]:0"
+            } else {
+                "0.0.0.0:0"
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(host)?;
+            Transport::Udp(socket)
+        } else {
+            let path = address.strip_prefix("unix:").unwrap_or(address);
+            if !path.starts_with('/') {
+                bail!("invalid syslog address: {}", address);
+            }
+            Transport::Unix(UnixDatagram::unbound()?, PathBuf::from(path))
+        };
+
+        let hostname = match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+            Ok(h) if !h.trim().is_empty() => h.trim().to_string(),
+            _ => "-".to_string(),
+        };
+
+        Ok(Syslog {
+            transport,
+            facility: facility_code(facility)?,
+            hostname,
+            pid: std::process::id(),
+        })
+    }
+}
+
+impl Append for Syslog {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let pri = self.facility as u32 * 8 + priority(record.level());
+        match &self.transport {
+            Transport::Unix(socket, path) => {
+                let now = chrono::Local::now().format("%b %e %H:%M:%S");
+                let msg = format!(
+                    "<{}>{} {}[{}]: {}",
+                    pri,
+                    now,
+                    IDENTIFIER,
+                    self.pid,
+                    record.args()
+                );
+                socket.send_to(msg.as_bytes(), path)?;
+            }
+            Transport::Udp(socket) => {
+                let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+                let msg = format!(
+                    "<{}>1 {} {} {} {} - - {}",
+                    pri,
+                    now,
+                    self.hostname,
+                    IDENTIFIER,
+                    self.pid,
+                    record.args()
+                );
+                socket.send(msg.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+// syslog severity
+fn priority(level: Level) -> u32 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn facility_code(facility: &str) -> MyResult<u8> {
+    let code = match facility {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => bail!("invalid syslog facility: {}", facility),
+    };
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_field() {
+        let mut buf = Vec::new();
+        journal_field(&mut buf, &journal_name("connection"), "1");
+        journal_field(&mut buf, &journal_name("hash_rate"), "2.5");
+        journal_field(&mut buf, "MESSAGE", "a\nb");
+        assert_eq!(
+            buf,
+            b"CONNECTION=1\nHASH_RATE=2.5\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n"
+        );
+        assert_eq!(journal_name("_x"), "F_X");
+        assert_eq!(facility_code("local3").unwrap(), 19);
+        assert!(facility_code("nothing").is_err());
+    }
+}
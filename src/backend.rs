@@ -0,0 +1,574 @@
+// backend.rs
+
+// Argon2d for the block digest with the compression written for
+// several instruction sets, the fastest one the CPU supports is chosen
+// at runtime so one binary runs everywhere; the rust-argon2 library
+// remains available and handles the other variants and versions
+
+use simple_error::bail;
+use std::sync::OnceLock;
+
+use super::block;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+const BLOCK_WORDS: usize = 128;
+
+type Block = [u64; BLOCK_WORDS];
+
+const SYNC_POINTS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Library,
+    Portable,
+    Sse41,
+    Avx2,
+}
+
+pub const NAMES: &[&str] = &["auto", "library", "portable", "sse4.1", "avx2"];
+
+static SELECTED: OnceLock<Backend> = OnceLock::new();
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Library => "library",
+            Backend::Portable => "portable",
+            Backend::Sse41 => "sse4.1",
+            Backend::Avx2 => "avx2",
+        }
+    }
+
+    pub fn supported(&self) -> bool {
+        match self {
+            Backend::Library | Backend::Portable => true,
+            #[cfg(target_arch = "x86_64")]
+            Backend::Sse41 => std::arch::is_x86_feature_detected!("sse4.1"),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx2 => std::arch::is_x86_feature_detected!("avx2"),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => false,
+        }
+    }
+}
+
+// the fastest supported
+pub fn detect() -> Backend {
+    [Backend::Avx2, Backend::Sse41]
+        .into_iter()
+        .find(|b| b.supported())
+        .unwrap_or(Backend::Portable)
+}
+
+// "auto" detects, otherwise the named backend which the CPU must
+// support; only the first selection takes effect
+pub fn select(name: &str) -> MyResult<Backend> {
+    let backend = match name {
+        "auto" => detect(),
+        "library" => Backend::Library,
+        "portable" => Backend::Portable,
+        "sse4.1" => Backend::Sse41,
+        "avx2" => Backend::Avx2,
+        other => bail!(
+            "invalid argon2_backend: {} expected one of: {}",
+            other,
+            NAMES.join(", ")
+        ),
+    };
+    if !backend.supported() {
+        bail!("argon2_backend: {} is not supported by this CPU", name);
+    }
+    Ok(*SELECTED.get_or_init(|| backend))
+}
+
+pub fn selected() -> Backend {
+    *SELECTED.get_or_init(detect)
+}
+
+// CPU features relevant to the choice, for the log
+pub fn features() -> Vec<&'static str> {
+    [Backend::Sse41, Backend::Avx2]
+        .into_iter()
+        .filter(|b| b.supported())
+        .map(|b| b.name())
+        .collect()
+}
+
+// None when the library must be used
+pub fn digest(backend: Backend, data: &[u8], p: &block::Params) -> Option<Vec<u8>> {
+    digests(backend, &[data], p).map(|mut d| d.remove(0))
+}
+
+// whether the backend computes these digests, and can interleave
+// them, rather than leaving them to the library
+pub fn handles(backend: Backend, p: &block::Params) -> bool {
+    backend != Backend::Library
+        && p.variant == argon2::Variant::Argon2d
+        && p.version == argon2::Version::Version13
+}
+
+// several digests computed together, each block of every memory in
+// turn so the memory accesses of one overlap the compression of the
+// others; None when the library must be used
+pub fn digests(backend: Backend, data: &[&[u8]], p: &block::Params) -> Option<Vec<Vec<u8>>> {
+    if !handles(backend, p) {
+        return None;
+    }
+
+    let lanes = p.lanes as usize;
+    let segment = p.memory as usize / (SYNC_POINTS * lanes);
+    let columns = segment * SYNC_POINTS;
+    let mut memories: Vec<Vec<Block>> = data.iter().map(|d| initial(d, p, columns)).collect();
+
+    match backend {
+        #[cfg(target_arch = "x86_64")]
+        Backend::Avx2 => unsafe { fill_avx2(&mut memories, lanes, segment, p.iterations) },
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse41 => unsafe { fill_sse41(&mut memories, lanes, segment, p.iterations) },
+        _ => fill(&mut memories, lanes, segment, p.iterations, rounds),
+    }
+
+    Some(memories.iter().map(|m| finish(m, lanes, columns)).collect())
+}
+
+// memory with the first two blocks of each lane set from H0
+fn initial(data: &[u8], p: &block::Params, columns: usize) -> Vec<Block> {
+    let lanes = p.lanes as usize;
+    let mut memory: Vec<Block> = vec![[0; BLOCK_WORDS]; lanes * columns];
+
+    let mut h0 = blake2b_simd::Params::new().hash_length(64).to_state();
+    for n in [p.lanes, 32, p.memory, p.iterations, 0x13, 0] {
+        h0.update(&n.to_le_bytes());
+    }
+    for s in [data, data, &[], &[]] {
+        h0.update(&(s.len() as u32).to_le_bytes());
+        h0.update(s);
+    }
+    let h0 = h0.finalize();
+
+    let mut seed = [0u8; 72];
+    seed[..64].copy_from_slice(h0.as_bytes());
+    for l in 0..lanes {
+        for j in 0..2 {
+            seed[64..68].copy_from_slice(&(j as u32).to_le_bytes());
+            seed[68..72].copy_from_slice(&(l as u32).to_le_bytes());
+            let bytes = long_hash(&seed, 1024);
+            for (w, c) in memory[l * columns + j]
+                .iter_mut()
+                .zip(bytes.chunks_exact(8))
+            {
+                *w = u64::from_le_bytes(c.try_into().unwrap());
+            }
+        }
+    }
+    memory
+}
+
+// the digest from the last block of each lane
+fn finish(memory: &[Block], lanes: usize, columns: usize) -> Vec<u8> {
+    let mut last = memory[columns - 1];
+    for l in 1..lanes {
+        for (w, x) in last
+            .iter_mut()
+            .zip(memory[l * columns + columns - 1].iter())
+        {
+            *w ^= x;
+        }
+    }
+    let bytes: Vec<u8> = last.iter().flat_map(|w| w.to_le_bytes()).collect();
+    long_hash(&bytes, 32)
+}
+
+// callers must check the CPU supports the instructions
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn fill_avx2(memories: &mut [Vec<Block>], lanes: usize, segment: usize, iterations: u32) {
+    fill(memories, lanes, segment, iterations, |q| unsafe {
+        x86::rounds_avx2(q)
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
+unsafe fn fill_sse41(memories: &mut [Vec<Block>], lanes: usize, segment: usize, iterations: u32) {
+    fill(memories, lanes, segment, iterations, |q| unsafe {
+        x86::rounds_sse41(q)
+    })
+}
+
+// with more than one memory the block each will reference next is
+// fetched into the cache while the others are compressed
+#[inline(always)]
+fn fill(
+    memories: &mut [Vec<Block>],
+    lanes: usize,
+    segment: usize,
+    iterations: u32,
+    rounds: impl Fn(&mut Block),
+) {
+    let columns = segment * SYNC_POINTS;
+    let interleaved = memories.len() > 1;
+    for pass in 0..iterations as usize {
+        for slice in 0..SYNC_POINTS {
+            for l in 0..lanes {
+                let first = if pass == 0 && slice == 0 { 2 } else { 0 };
+                for i in first..segment {
+                    let j = slice * segment + i;
+                    let current = l * columns + j;
+                    let previous = if j == 0 {
+                        current + columns - 1
+                    } else {
+                        current - 1
+                    };
+                    for memory in memories.iter_mut() {
+                        let random = memory[previous][0];
+                        let reference = reference_index(random, pass, slice, l, i, lanes, segment);
+                        compress(memory, current, previous, reference, pass > 0, &rounds);
+                        if interleaved && i + 1 < segment {
+                            let random = memory[current][0];
+                            let next =
+                                reference_index(random, pass, slice, l, i + 1, lanes, segment);
+                            prefetch(&memory[next]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn prefetch(block: &Block) {
+    #[cfg(target_arch = "x86_64")]
+    for line in block.chunks_exact(8) {
+        // baseline on x86_64, only a hint
+        unsafe {
+            std::arch::x86_64::_mm_prefetch::<{ std::arch::x86_64::_MM_HINT_T0 }>(
+                line.as_ptr() as *const i8
+            )
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = block;
+}
+
+// RFC 9106 section 3.4.1.2 with the data dependent J1, J2
+#[inline(always)]
+fn reference_index(
+    random: u64,
+    pass: usize,
+    slice: usize,
+    lane: usize,
+    index: usize,
+    lanes: usize,
+    segment: usize,
+) -> usize {
+    let columns = segment * SYNC_POINTS;
+    let j1 = random & 0xffffffff;
+    let ref_lane = if pass == 0 && slice == 0 {
+        lane
+    } else {
+        ((random >> 32) % lanes as u64) as usize
+    };
+    let same = ref_lane == lane;
+    let finished = if pass == 0 {
+        slice * segment
+    } else {
+        columns - segment
+    };
+    let area = if same {
+        finished + index - 1
+    } else {
+        finished - usize::from(index == 0)
+    } as u64;
+    let relative = area - 1 - ((area * ((j1 * j1) >> 32)) >> 32);
+    let start = if pass == 0 || slice == SYNC_POINTS - 1 {
+        0
+    } else {
+        (slice + 1) * segment
+    };
+    ref_lane * columns + (start + relative as usize) % columns
+}
+
+#[inline(always)]
+fn compress(
+    memory: &mut [Block],
+    current: usize,
+    previous: usize,
+    reference: usize,
+    xor: bool,
+    rounds: &impl Fn(&mut Block),
+) {
+    let mut r = [0u64; BLOCK_WORDS];
+    for (k, w) in r.iter_mut().enumerate() {
+        *w = memory[previous][k] ^ memory[reference][k];
+    }
+    let mut q = r;
+    rounds(&mut q);
+    let out = &mut memory[current];
+    if xor {
+        for k in 0..BLOCK_WORDS {
+            out[k] ^= q[k] ^ r[k];
+        }
+    } else {
+        for k in 0..BLOCK_WORDS {
+            out[k] = q[k] ^ r[k];
+        }
+    }
+}
+
+// the BLAKE2b rounds over the eight rows of 16 words then the eight
+// columns of 16 words, taken two adjacent words from each row
+fn rounds(q: &mut Block) {
+    for row in 0..8 {
+        permute(q, |k| 16 * row + k);
+    }
+    for column in 0..8 {
+        permute(q, |k| 2 * column + 16 * (k / 2) + k % 2);
+    }
+}
+
+// the BLAKE2b round on 16 words picked out by index
+#[inline(always)]
+fn permute(v: &mut Block, at: impl Fn(usize) -> usize) {
+    const MIX: [[usize; 4]; 8] = [
+        [0, 4, 8, 12],
+        [1, 5, 9, 13],
+        [2, 6, 10, 14],
+        [3, 7, 11, 15],
+        [0, 5, 10, 15],
+        [1, 6, 11, 12],
+        [2, 7, 8, 13],
+        [3, 4, 9, 14],
+    ];
+    for [a, b, c, d] in MIX {
+        let (a, b, c, d) = (at(a), at(b), at(c), at(d));
+        v[a] = blamka(v[a], v[b]);
+        v[d] = (v[d] ^ v[a]).rotate_right(32);
+        v[c] = blamka(v[c], v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(24);
+        v[a] = blamka(v[a], v[b]);
+        v[d] = (v[d] ^ v[a]).rotate_right(16);
+        v[c] = blamka(v[c], v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(63);
+    }
+}
+
+#[inline(always)]
+fn blamka(x: u64, y: u64) -> u64 {
+    let m = (x & 0xffffffff) * (y & 0xffffffff);
+    x.wrapping_add(y).wrapping_add(m.wrapping_mul(2))
+}
+
+// BLAKE2b of any output length
+fn long_hash(input: &[u8], length: usize) -> Vec<u8> {
+    let hash = |n: usize, parts: &[&[u8]]| {
+        let mut state = blake2b_simd::Params::new().hash_length(n).to_state();
+        for p in parts {
+            state.update(p);
+        }
+        state.finalize().as_bytes().to_vec()
+    };
+    let prefix = (length as u32).to_le_bytes();
+    if length <= 64 {
+        return hash(length, &[&prefix, input]);
+    }
+    let mut v = hash(64, &[&prefix, input]);
+    let mut out = Vec::with_capacity(length);
+    while length - out.len() > 64 {
+        out.extend_from_slice(&v[..32]);
+        let remaining = length - out.len();
+        v = hash(remaining.min(64), &[&v]);
+    }
+    out.extend_from_slice(&v);
+    out
+}
+
+// the rounds with the 16 words as four vectors a, b, c, d of four
+// words, mixing the four columns at once then, with b, c and d rotated
+// by one, two and three words, the four diagonals
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::Block;
+
+    // word offsets of the eight pairs of words forming a row or column
+    #[inline(always)]
+    fn pairs(n: usize, column: bool) -> [usize; 8] {
+        std::array::from_fn(|i| {
+            if column {
+                2 * n + 16 * i
+            } else {
+                16 * n + 2 * i
+            }
+        })
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn rounds_avx2(q: &mut Block) {
+        for column in [false, true] {
+            for n in 0..8 {
+                let at = pairs(n, column);
+                let p = q.as_mut_ptr();
+                let load = |i: usize| unsafe {
+                    _mm256_set_m128i(
+                        _mm_loadu_si128(p.add(at[i + 1]) as *const __m128i),
+                        _mm_loadu_si128(p.add(at[i]) as *const __m128i),
+                    )
+                };
+                let (mut a, mut b, mut c, mut d) = (load(0), load(2), load(4), load(6));
+                mix_avx2(&mut a, &mut b, &mut c, &mut d);
+                b = _mm256_permute4x64_epi64::<0x39>(b);
+                c = _mm256_permute4x64_epi64::<0x4e>(c);
+                d = _mm256_permute4x64_epi64::<0x93>(d);
+                mix_avx2(&mut a, &mut b, &mut c, &mut d);
+                b = _mm256_permute4x64_epi64::<0x93>(b);
+                c = _mm256_permute4x64_epi64::<0x4e>(c);
+                d = _mm256_permute4x64_epi64::<0x39>(d);
+                for (i, v) in [a, b, c, d].into_iter().enumerate() {
+                    _mm_storeu_si128(p.add(at[2 * i]) as *mut __m128i, _mm256_castsi256_si128(v));
+                    _mm_storeu_si128(
+                        p.add(at[2 * i + 1]) as *mut __m128i,
+                        _mm256_extracti128_si256::<1>(v),
+                    );
+                }
+            }
+        }
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    fn mix_avx2(a: &mut __m256i, b: &mut __m256i, c: &mut __m256i, d: &mut __m256i) {
+        let blamka = |x, y| {
+            let m = _mm256_mul_epu32(x, y);
+            _mm256_add_epi64(_mm256_add_epi64(x, y), _mm256_add_epi64(m, m))
+        };
+        *a = blamka(*a, *b);
+        *d = _mm256_shuffle_epi32::<0xb1>(_mm256_xor_si256(*d, *a));
+        *c = blamka(*c, *d);
+        let x = _mm256_xor_si256(*b, *c);
+        *b = _mm256_or_si256(_mm256_srli_epi64::<24>(x), _mm256_slli_epi64::<40>(x));
+        *a = blamka(*a, *b);
+        let x = _mm256_xor_si256(*d, *a);
+        *d = _mm256_or_si256(_mm256_srli_epi64::<16>(x), _mm256_slli_epi64::<48>(x));
+        *c = blamka(*c, *d);
+        let x = _mm256_xor_si256(*b, *c);
+        *b = _mm256_or_si256(_mm256_srli_epi64::<63>(x), _mm256_add_epi64(x, x));
+    }
+
+    // each vector is two registers of two words
+    #[target_feature(enable = "sse4.1")]
+    pub unsafe fn rounds_sse41(q: &mut Block) {
+        for column in [false, true] {
+            for n in 0..8 {
+                let at = pairs(n, column);
+                let p = q.as_mut_ptr();
+                let mut v: [__m128i; 8] = std::array::from_fn(|i| unsafe {
+                    _mm_loadu_si128(p.add(at[i]) as *const __m128i)
+                });
+                mix_sse41(&mut v);
+                // rotate b left by one, c by two and d by three words
+                let [_, _, b0, b1, c0, c1, d0, d1] = v;
+                v[2] = _mm_alignr_epi8::<8>(b1, b0);
+                v[3] = _mm_alignr_epi8::<8>(b0, b1);
+                v[4] = c1;
+                v[5] = c0;
+                v[6] = _mm_alignr_epi8::<8>(d0, d1);
+                v[7] = _mm_alignr_epi8::<8>(d1, d0);
+                mix_sse41(&mut v);
+                let [_, _, b0, b1, c0, c1, d0, d1] = v;
+                v[2] = _mm_alignr_epi8::<8>(b0, b1);
+                v[3] = _mm_alignr_epi8::<8>(b1, b0);
+                v[4] = c1;
+                v[5] = c0;
+                v[6] = _mm_alignr_epi8::<8>(d1, d0);
+                v[7] = _mm_alignr_epi8::<8>(d0, d1);
+                for (i, x) in v.into_iter().enumerate() {
+                    _mm_storeu_si128(p.add(at[i]) as *mut __m128i, x);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.1")]
+    fn mix_sse41(v: &mut [__m128i; 8]) {
+        let blamka = |x, y| {
+            let m = _mm_mul_epu32(x, y);
+            _mm_add_epi64(_mm_add_epi64(x, y), _mm_add_epi64(m, m))
+        };
+        for h in 0..2 {
+            let (mut a, mut b, mut c, mut d) = (v[h], v[2 + h], v[4 + h], v[6 + h]);
+            a = blamka(a, b);
+            d = _mm_shuffle_epi32::<0xb1>(_mm_xor_si128(d, a));
+            c = blamka(c, d);
+            let x = _mm_xor_si128(b, c);
+            b = _mm_or_si128(_mm_srli_epi64::<24>(x), _mm_slli_epi64::<40>(x));
+            a = blamka(a, b);
+            let x = _mm_xor_si128(d, a);
+            d = _mm_or_si128(_mm_srli_epi64::<16>(x), _mm_slli_epi64::<48>(x));
+            c = blamka(c, d);
+            let x = _mm_xor_si128(b, c);
+            b = _mm_or_si128(_mm_srli_epi64::<63>(x), _mm_add_epi64(x, x));
+            (v[h], v[2 + h], v[4 + h], v[6 + h]) = (a, b, c, d);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends() {
+        let data = b"0123456789abcdef0123456789abcdef";
+        let small = block::Params {
+            memory: 64,
+            iterations: 3,
+            ..block::LIVE
+        };
+        let lanes = block::Params {
+            memory: 256,
+            lanes: 4,
+            ..small
+        };
+        let id = block::Params {
+            variant: argon2::Variant::Argon2id,
+            ..small
+        };
+        for b in [Backend::Portable, Backend::Sse41, Backend::Avx2] {
+            if !b.supported() {
+                continue;
+            }
+            for p in [&small, &lanes] {
+                assert_eq!(
+                    digest(b, data, p).unwrap(),
+                    block::library_digest(data, p),
+                    "{}",
+                    b.name()
+                );
+            }
+            assert_eq!(digest(b, data, &id), None);
+
+            // interleaved the same as one at a time
+            let other = b"fedcba9876543210fedcba9876543210";
+            assert_eq!(
+                digests(b, &[data, other, data], &lanes).unwrap(),
+                vec![
+                    block::library_digest(data, &lanes),
+                    block::library_digest(other, &lanes),
+                    block::library_digest(data, &lanes),
+                ]
+            );
+        }
+        assert_eq!(digest(Backend::Library, data, &small), None);
+        assert!(!handles(Backend::Library, &small));
+        assert!(!handles(Backend::Portable, &id));
+        assert!(handles(Backend::Portable, &small));
+
+        assert!(select("mmx").is_err());
+        assert!(detect().supported());
+    }
+}
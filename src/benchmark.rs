@@ -0,0 +1,138 @@
+// benchmark.rs
+
+// hash rate of a connection's digest for combinations of threads and
+// interleaved digests per thread, to choose between more workers and
+// interleaving on this host
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::backend;
+use super::block;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+pub struct Options {
+    pub threads: Vec<u32>,
+    pub interleave: Vec<u32>,
+    pub duration: Duration,
+}
+
+struct Measured {
+    hashes: u64,
+    rate: f64,
+}
+
+// threads each hashing batches of consecutive nonces until stopped,
+// only whole batches are counted
+fn measure(params: &block::Params, threads: u32, interleave: u32, duration: Duration) -> Measured {
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = Instant::now();
+    let handles: Vec<std::thread::JoinHandle<u64>> = (0..threads as u64)
+        .map(|t| {
+            let stop = stop.clone();
+            let params = *params;
+            std::thread::spawn(move || {
+                let mut nonce = t << 32;
+                let mut hashes = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    let batch: Vec<Vec<u8>> = (0..interleave as u64)
+                        .map(|k| {
+                            let mut b = vec![0x5a; 92];
+                            b.extend_from_slice(&(nonce + k).to_le_bytes());
+                            b
+                        })
+                        .collect();
+                    let data: Vec<&[u8]> = batch.iter().map(|b| &b[..]).collect();
+                    block::digests(&data, &params);
+                    nonce += interleave as u64;
+                    hashes += interleave as u64;
+                }
+                hashes
+            })
+        })
+        .collect();
+    std::thread::sleep(duration);
+    stop.store(true, Ordering::Relaxed);
+    let hashes = handles.into_iter().map(|h| h.join().unwrap_or(0)).sum();
+    Measured {
+        hashes,
+        rate: hashes as f64 / start.elapsed().as_secs_f64(),
+    }
+}
+
+// a table of every combination that fits in memory, the fastest marked
+pub fn run(params: &block::Params, options: &Options, memory: u64) -> MyResult<String> {
+    let mut s = format!(
+        "argon2 backend: {}  memory: {} KiB  iterations: {}  lanes: {}\n",
+        backend::selected().name(),
+        params.memory,
+        params.iterations,
+        params.lanes
+    );
+    s += "threads  interleave  memory MiB    hashes     H/s  H/s/thread\n";
+
+    // None where it does not fit in memory
+    let mut results = Vec::new();
+    for &threads in &options.threads {
+        for &interleave in &options.interleave {
+            let needed = params.memory as u64 * 1024 * (threads * interleave) as u64;
+            let m = if needed > memory {
+                None
+            } else {
+                log::info!(
+                    "benchmark: threads: {}  interleave: {}",
+                    threads,
+                    interleave
+                );
+                Some(measure(params, threads, interleave, options.duration))
+            };
+            results.push((threads, interleave, needed, m));
+        }
+    }
+
+    let best = results
+        .iter()
+        .filter_map(|(_, _, _, m)| m.as_ref().map(|m| m.rate))
+        .fold(0.0, f64::max);
+    for (threads, interleave, needed, m) in &results {
+        s += &format!("{:7}  {:10}  {:10}  ", threads, interleave, needed >> 20);
+        s += &match m {
+            Some(m) => format!(
+                "{:8}  {:6.2}  {:10.2}{}\n",
+                m.hashes,
+                m.rate,
+                m.rate / *threads as f64,
+                if m.rate == best { "  *" } else { "" }
+            ),
+            None => "skipped: not enough memory\n".to_string(),
+        };
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let params = block::Params {
+            memory: 64,
+            iterations: 1,
+            ..block::LIVE
+        };
+        let options = Options {
+            threads: vec![1, 2],
+            interleave: vec![1, 4],
+            duration: Duration::from_millis(50),
+        };
+        // 2 threads of 4 need 512 KiB
+        let table = run(&params, &options, 256 << 10).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[5].contains("skipped"));
+        assert_eq!(lines.iter().filter(|l| l.ends_with('*')).count(), 1);
+    }
+}
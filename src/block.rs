@@ -7,13 +7,20 @@ use bytes::BufMut;
 use hex_serde;
 use serde_aux::prelude::deserialize_number_from_string;
 use serde_derive::{Deserialize, Serialize};
+use simple_error::bail;
+
+use super::backend;
+use super::config;
 
 //use argonautica::config::{Backend, Variant, Version};
 //use argonautica::Hasher;
 //use futures_cpupool::CpuPool;
 
 base64_serde_type!(Base64Standard, base64::engine::general_purpose::STANDARD);
-#[derive(Debug, Serialize, Deserialize)]
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Header {
     #[serde(rename = "version")]
     pub version: u16,
@@ -57,21 +64,162 @@ impl From<Header> for bytes::Bytes {
     }
 }
 
-// if using argon2
-pub fn block_digest(data: &[u8]) -> std::vec::Vec<u8> {
-    // const (
-    // 	digestMode        = argon2.ModeArgon2d
-    // 	digestMemory      = 1 << 17 // 128 MiB
-    // 	digestParallelism = 1
-    // 	digestIterations  = 4
-    // 	digestVersion     = argon2.Version13
-    // )
+impl Header {
+    // the wire format: 92 bytes without the nonce, which is then zero,
+    // or 100 bytes with it
+    pub fn parse(b: &[u8]) -> MyResult<Self> {
+        if b.len() != 92 && b.len() != 100 {
+            bail!("header is: {} bytes, expected 92 or 100", b.len());
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        Ok(Header {
+            version: u16::from_le_bytes([b[0], b[1]]),
+            transaction_count: u16::from_le_bytes([b[2], b[3]]),
+            number: u64_at(4),
+            previous_block: b[12..44].try_into().unwrap(),
+            merkle_root: b[44..76].try_into().unwrap(),
+            timestamp: u64_at(76),
+            difficulty: b[84..92].try_into().unwrap(),
+            nonce: match b.len() {
+                100 => b[92..100].try_into().unwrap(),
+                _ => [0; 8],
+            },
+        })
+    }
+}
+
+impl std::fmt::Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let date = match chrono::DateTime::from_timestamp(self.timestamp as i64, 0) {
+            Some(d) => d.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            None => "invalid".to_string(),
+        };
+        writeln!(f, "version:           {}", self.version)?;
+        writeln!(f, "transaction count: {}", self.transaction_count)?;
+        writeln!(f, "number:            {}", self.number)?;
+        writeln!(f, "previous block:    {}", hex::encode(self.previous_block))?;
+        writeln!(f, "merkle root:       {}", hex::encode(self.merkle_root))?;
+        writeln!(f, "timestamp:         {}  {}", self.timestamp, date)?;
+        writeln!(f, "difficulty:        {}", hex::encode(self.difficulty))?;
+        writeln!(
+            f,
+            "nonce:             {}",
+            nonce_hex(u64::from_le_bytes(self.nonce))
+        )
+    }
+}
+
+// Argon2 memory per hash in KiB (128 MiB)
+pub const DIGEST_MEMORY: u32 = 1 << 17;
+
+pub const DIGEST_ITERATIONS: u32 = 4;
+
+pub const DIGEST_LANES: u32 = 1;
+
+// Argon2 parameters of the block digest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Params {
+    pub variant: Variant,
+    pub version: Version,
+    pub memory: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+}
+
+// as bitmarkd:
+// const (
+// 	digestMode        = argon2.ModeArgon2d
+// 	digestMemory      = 1 << 17 // 128 MiB
+// 	digestParallelism = 1
+// 	digestIterations  = 4
+// 	digestVersion     = argon2.Version13
+// )
+pub const LIVE: Params = Params {
+    variant: Variant::Argon2d,
+    version: Version::Version13,
+    memory: DIGEST_MEMORY,
+    iterations: DIGEST_ITERATIONS,
+    lanes: DIGEST_LANES,
+};
+
+impl Default for Params {
+    fn default() -> Self {
+        LIVE
+    }
+}
+
+impl Params {
+    // checked here since hashing with invalid values would panic
+    pub fn new(c: &config::Argon2) -> MyResult<Self> {
+        let variant = match c.variant.as_str() {
+            "argon2d" => Variant::Argon2d,
+            "argon2i" => Variant::Argon2i,
+            "argon2id" => Variant::Argon2id,
+            other => bail!("invalid argon2 variant: {}", other),
+        };
+        let version = match c.version {
+            0x10 => Version::Version10,
+            0x13 => Version::Version13,
+            other => bail!("invalid argon2 version: 0x{:x}", other),
+        };
+        if c.lanes < 1 || c.lanes > 0xffffff {
+            bail!("invalid argon2 lanes: {}", c.lanes);
+        }
+        if c.memory < 8 * c.lanes {
+            bail!(
+                "invalid argon2 memory: {} KiB, at least: {} for: {} lanes",
+                c.memory,
+                8 * c.lanes,
+                c.lanes
+            );
+        }
+        if c.iterations < 1 {
+            bail!("invalid argon2 iterations: {}", c.iterations);
+        }
+        Ok(Params {
+            variant,
+            version,
+            memory: c.memory,
+            iterations: c.iterations,
+            lanes: c.lanes,
+        })
+    }
+}
+
+// a nonce as it appears in logs, hooks and the journal: the integer
+// in hex, not its little endian bytes
+pub fn nonce_hex(nonce: u64) -> String {
+    format!("{:016x}", nonce)
+}
+
+// the nonce of a submission, packed little endian
+pub fn packed_nonce(packed: &[u8]) -> u64 {
+    u64::from_le_bytes(packed.try_into().unwrap_or_default())
+}
+
+// with the backend selected for this CPU where it handles the parameters
+pub fn digest(data: &[u8], p: &Params) -> std::vec::Vec<u8> {
+    match backend::digest(backend::selected(), data, p) {
+        Some(d) => d,
+        None => library_digest(data, p),
+    }
+}
+
+// the digests of several headers computed together
+pub fn digests(data: &[&[u8]], p: &Params) -> std::vec::Vec<std::vec::Vec<u8>> {
+    match backend::digests(backend::selected(), data, p) {
+        Some(d) => d,
+        None => data.iter().map(|d| library_digest(d, p)).collect(),
+    }
+}
+
+pub fn library_digest(data: &[u8], p: &Params) -> std::vec::Vec<u8> {
     let config = Config {
-        variant: Variant::Argon2d,
-        version: Version::Version13,
-        mem_cost: 1 << 17,
-        time_cost: 4,
-        lanes: 1,
+        variant: p.variant,
+        version: p.version,
+        mem_cost: p.memory,
+        time_cost: p.iterations,
+        lanes: p.lanes,
         secret: &[],
         ad: &[],
         hash_length: 32,
@@ -80,6 +228,30 @@ pub fn block_digest(data: &[u8]) -> std::vec::Vec<u8> {
     argon2::hash_raw(data, data, &config).unwrap()
 }
 
+// difficulty is a little endian u64 of an 8 bit exponent above a 56
+// bit mantissa, the target is the mantissa shifted left by 192 less
+// the exponent; returned big endian for comparing
+pub fn difficulty_target(difficulty: &[u8; 8]) -> [u8; 32] {
+    let bits = u64::from_le_bytes(*difficulty);
+    let exponent = (bits >> 56) as i32;
+    let mantissa = bits & 0x00ff_ffff_ffff_ffff;
+    let mut target = [0u8; 32];
+    for i in 0..56 {
+        let n = i + 192 - exponent;
+        if mantissa & (1 << i) != 0 && (0..256).contains(&n) {
+            target[31 - n as usize / 8] |= 1 << (n % 8);
+        }
+    }
+    target
+}
+
+// the digest is a little endian number that must not exceed the target
+pub fn satisfies(digest: &[u8], target: &[u8; 32]) -> bool {
+    let mut value = digest.to_vec();
+    value.reverse();
+    value.as_slice() <= target.as_slice()
+}
+
 // if using argonautica
 // pub fn block_digest(data: &[u8]) -> std::vec::Vec<u8> {
 //     let mut hasher = Hasher::default();
@@ -153,7 +325,7 @@ mod tests {
             nonce: [0x11, 0x5a, 0x38, 0xbf, 0x3a, 0x90, 0x9f, 0xe1],
         };
 
-        let buf = bytes::Bytes::from(h);
+        let buf = bytes::Bytes::from(h.clone());
         assert_eq!(buf.len(), 100 - 8);
         assert_eq!(live_genesis_block[0..92], buf[..]);
 
@@ -166,8 +338,36 @@ mod tests {
         assert_eq!(buf2.len(), 100);
         assert_eq!(live_genesis_block[0..100], buf2[..]);
 
-        let digest = block_digest(&buf2);
+        let digest = digest(&buf2, &LIVE);
         assert_eq!(digest, live_genesis_digest);
+
+        // and back again, with and without the nonce
+        assert_eq!(Header::parse(&buf2).unwrap(), h);
+        let mut bare = Header::parse(&buf).unwrap();
+        assert_eq!(bare.nonce, [0; 8]);
+        assert_eq!(bytes::Bytes::from(bare.clone()), buf);
+        bare.nonce = h.nonce;
+        assert_eq!(bare, h);
+        assert!(Header::parse(&buf2[..99]).is_err());
+        assert!(h
+            .to_string()
+            .contains("timestamp:         1451268791  2015-12-28 02:13:11 UTC\n"));
+
+        let difficulty: [u8; 8] = live_genesis_block[84..92].try_into().unwrap();
+        let target = difficulty_target(&difficulty);
+        assert_eq!(
+            hex::encode(target),
+            "00ffffffffffffff000000000000000000000000000000000000000000000000"
+        );
+        assert!(satisfies(&digest, &target));
+        let mut harder = difficulty;
+        harder[7] = 0x10;
+        assert!(!satisfies(&digest, &difficulty_target(&harder)));
+
+        // the same nonce from the worker and from a submission
+        let nonce = 0x0123456789abcdef;
+        assert_eq!(nonce_hex(nonce), "0123456789abcdef");
+        assert_eq!(packed_nonce(&u64::to_le_bytes(nonce)), nonce);
     }
 
     // #[test]
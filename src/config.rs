@@ -1,52 +1,178 @@
 // config.rs
 
-use rlua::{Lua, Result, Table};
+use rlua::{FromLua, Lua, Result, Table};
+use serde_derive::Serialize;
+use simple_error::bail;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::sync::Arc;
 
-#[derive(Debug, PartialEq)]
+use super::block;
+use super::hooks;
+
+#[derive(Debug, PartialEq, Serialize)]
 pub struct Configuration {
     pub data_directory: String,
+    pub daemon: bool,
+    pub pidfile: String,
+    pub control_socket: String,
+    pub journal: String,
+    pub user: String,
+    pub group: String,
+    pub memory_check: String,
+    pub stall_seconds: u64,
+    pub verify_digest: bool,
+    pub self_test_interval: u64,
+    pub argon2_backend: String,
     pub connections: Vec<Connection>,
     pub logging: Logging,
+    pub throttle: Throttle,
+    pub hooks: Arc<hooks::Hooks>,
+
+    // keys that were not set by the configuration file
+    pub defaults: Vec<String>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize)]
 pub struct Connection {
     pub number: i64, // 1..=n
     pub enable: bool,
     pub workers: u32,
+    pub auto_workers: bool,
     pub use_ipv4: bool,
     pub host: String,
     pub public_key: String,
     pub subscribe_port: u16,
     pub request_port: u16,
+    pub schedule: Vec<String>,
+    pub record: String,
+    pub dry_run: bool,
+    pub interleave: u32,
+    pub argon2: Argon2,
+}
+
+// block digest parameters, bitmarkd's unless hashing for a private chain
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Argon2 {
+    pub variant: String,
+    pub version: u32,
+    pub memory: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+}
+
+impl Default for Argon2 {
+    fn default() -> Self {
+        Argon2 {
+            variant: DEFAULT_ARGON2_VARIANT.to_string(),
+            version: DEFAULT_ARGON2_VERSION,
+            memory: block::DIGEST_MEMORY,
+            iterations: block::DIGEST_ITERATIONS,
+            lanes: block::DIGEST_LANES,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize)]
 pub struct Logging {
+    #[serde(rename = "data_directory")]
     pub directory: String,
     pub file: String,
     pub size: u64,
     pub count: u32,
+    pub rotate: String,
+    pub compress: bool,
+    pub retention_days: u64,
     pub console: bool,
     pub level: String,
+    pub format: String,
+    pub pattern: String,
+    pub time: String,
+    pub console_level: String,
+    pub file_level: String,
+    // only a level that was set filters its appender, otherwise the
+    // module levels apply to it too
+    #[serde(skip)]
+    pub console_level_set: bool,
+    #[serde(skip)]
+    pub file_level_set: bool,
+    pub modules: BTreeMap<String, String>,
+    pub output: Vec<String>,
+    pub syslog_address: String,
+    pub syslog_facility: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Throttle {
+    pub enable: bool,
+    pub mode: String,
+    pub interval: u64,
+    pub load: f64,
+    pub memory_pressure: f64,
+    pub temperature: f64,
+    pub loadavg_path: String,
+    pub pressure_path: String,
+    pub thermal_path: String,
 }
 
-const DEFAULT_DATA_DIRECTORY: &str = ".";
+pub const DEFAULT_DATA_DIRECTORY: &str = ".";
+pub const DEFAULT_PIDFILE: &str = "mt-recorder.pid";
+pub const DEFAULT_CONTROL_SOCKET: &str = "mt-recorder.sock";
+pub const DEFAULT_JOURNAL: &str = "found.jsonl";
 
-const DEFAULT_PUBLISH: u16 = 2138;
-const DEFAULT_REQUEST: u16 = 2139;
-const DEFAULT_WORKERS: u32 = 1;
+pub const DEFAULT_PUBLISH: u16 = 2138;
+pub const DEFAULT_REQUEST: u16 = 2139;
+pub const DEFAULT_WORKERS: u32 = 1;
+pub const DEFAULT_MEMORY_CHECK: &str = "refuse";
+pub const DEFAULT_STALL_SECONDS: u64 = 60;
+pub const DEFAULT_SELF_TEST_INTERVAL: u64 = 3600;
+pub const DEFAULT_ARGON2_BACKEND: &str = "auto";
+pub const DEFAULT_INTERLEAVE: u32 = 1;
+pub const MAXIMUM_INTERLEAVE: u32 = 8;
+pub const DEFAULT_ARGON2_VARIANT: &str = "argon2d";
+pub const DEFAULT_ARGON2_VERSION: u32 = 0x13;
 
-const DEFAULT_LOG_DIRECTORY: &str = "log";
-const DEFAULT_LOG_FILE: &str = "mt-recorder.log";
-const DEFAULT_LOG_SIZE: u64 = 10000;
-const DEFAULT_LOG_COUNT: u32 = 1;
+pub const DEFAULT_LOG_DIRECTORY: &str = "log";
+pub const DEFAULT_LOG_FILE: &str = "mt-recorder.log";
+pub const DEFAULT_LOG_SIZE: u64 = 1048576;
+pub const DEFAULT_LOG_COUNT: u32 = 10;
+pub const DEFAULT_LOG_ROTATE: &str = "size";
+pub const DEFAULT_LOG_RETENTION_DAYS: u64 = 0;
+pub const DEFAULT_LOG_LEVEL: &str = "warn";
+pub const DEFAULT_LOG_FORMAT: &str = "text";
+pub const DEFAULT_LOG_PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S)} [{l}] {M}: {m}{n}";
+pub const DEFAULT_LOG_TIME: &str = "utc";
+pub const DEFAULT_LOG_OUTPUT: &str = "file";
+pub const DEFAULT_SYSLOG_ADDRESS: &str = "unix:/dev/log";
+pub const DEFAULT_SYSLOG_FACILITY: &str = "daemon";
+
+pub const DEFAULT_THROTTLE_MODE: &str = "duty";
+pub const DEFAULT_THROTTLE_INTERVAL: u64 = 10;
+pub const DEFAULT_LOADAVG_PATH: &str = "/proc/loadavg";
+pub const DEFAULT_PRESSURE_PATH: &str = "/proc/pressure/memory";
+pub const DEFAULT_THERMAL_PATH: &str = "/sys/class/thermal";
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+const LOG_TIMES: [&str; 2] = ["utc", "local"];
 
 // allow use of '?' to quick return error
-//type MyResult<T> = Result<T, Box<Error>>;
+type MyResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// values from the command line or environment, these take precedence
+// over the configuration file:
+//   defaults < configuration file < environment < command line
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub log_level: Option<String>,
+    pub log_console: Option<bool>,
+    pub daemon: Option<bool>,
+    pub dry_run: bool,
+    pub workers: Vec<(i64, u32)>,
+    pub enable: Vec<i64>,
+    pub disable: Vec<i64>,
+}
 
 pub fn read(filename: &str, debug: bool) -> Result<Configuration> {
     if debug {
@@ -63,7 +189,7 @@ pub fn read(filename: &str, debug: bool) -> Result<Configuration> {
     }
 
     let lua = Lua::new();
-    let result = lua.context(|lua| {
+    let (mut result, keys) = lua.context(|lua| {
         let arg = lua.create_table()?;
         arg.set(0, filename)?;
 
@@ -72,96 +198,582 @@ pub fn read(filename: &str, debug: bool) -> Result<Configuration> {
 
         let config = lua.load(&contents).set_name("config")?.eval::<Table>()?;
 
-        let mut data_directory: String = config
-            .get::<_, String>("data_directory")
-            .unwrap_or(DEFAULT_DATA_DIRECTORY.to_string())
-            .trim()
-            .to_string();
-        if data_directory.is_empty() {
-            data_directory = DEFAULT_DATA_DIRECTORY.to_string()
-        }
+        let mut r = Reader::default();
+
+        let data_directory = r.string(&config, "", "data_directory", DEFAULT_DATA_DIRECTORY)?;
+        let daemon = r.get(&config, "", "daemon", false)?;
+        let pidfile = data_path(
+            &data_directory,
+            r.string(&config, "", "pidfile", DEFAULT_PIDFILE)?,
+        );
+        let control_socket = data_path(
+            &data_directory,
+            r.string(&config, "", "control_socket", DEFAULT_CONTROL_SOCKET)?,
+        );
+        let journal = data_path(
+            &data_directory,
+            r.string(&config, "", "journal", DEFAULT_JOURNAL)?,
+        );
+        let user = r.string(&config, "", "user", "")?;
+        let group = r.string(&config, "", "group", "")?;
+        let memory_check = r.string(&config, "", "memory_check", DEFAULT_MEMORY_CHECK)?;
+        let stall_seconds = r.get(&config, "", "stall_seconds", DEFAULT_STALL_SECONDS)?;
+        let verify_digest = r.get(&config, "", "verify_digest", false)?;
+        let self_test_interval = r.get(
+            &config,
+            "",
+            "self_test_interval",
+            DEFAULT_SELF_TEST_INTERVAL,
+        )?;
+        let argon2_backend = r.string(&config, "", "argon2_backend", DEFAULT_ARGON2_BACKEND)?;
 
         let connections: Table = config.get("connections")?;
-        let logging: Table = config.get("logging")?;
+        let logging: Table = match config.get::<_, Option<Table>>("logging")? {
+            Some(t) => t,
+            None => lua.create_table()?,
+        };
+
+        // hashing windows for connections without their own
+        let schedule: Vec<String> = r.get(&config, "", "schedule", Vec::new())?;
 
         let mut cn = Vec::new();
 
         //for connection in connections {
         for i in 1..=connections.len()? {
             let connection: Table = connections.get(i)?;
+            let path = format!("connections[{}]", i);
+            // a number or "auto" to be resolved from the host resources
+            let workers = r.string(&connection, &path, "workers", &DEFAULT_WORKERS.to_string())?;
+            let auto_workers = workers == "auto";
+            let workers = match workers.parse::<u32>() {
+                Ok(w) => w,
+                Err(_) if auto_workers => 0,
+                Err(_) => {
+                    return Err(rlua::Error::RuntimeError(format!(
+                        "{}.workers: invalid value: {}",
+                        path, workers
+                    )))
+                }
+            };
+            let interleave = r.get(&connection, &path, "interleave", DEFAULT_INTERLEAVE)?;
+            if !(1..=MAXIMUM_INTERLEAVE).contains(&interleave) {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "{}.interleave: invalid value: {}",
+                    path, interleave
+                )));
+            }
             let c = Connection {
                 number: i,
-                enable: match connection.get::<_, bool>("enable") {
-                    Ok(n) => n,
-                    Err(_) => false,
-                },
+                enable: r.get(&connection, &path, "enable", false)?,
                 host: connection.get("host")?,
                 public_key: connection
                     .get::<_, String>("public_key")?
                     .trim()
                     .replace("PUBLIC:", ""),
-                subscribe_port: connection
-                    .get::<_, String>("subscribe_port")?
-                    .parse::<u16>()
-                    .unwrap_or(DEFAULT_PUBLISH),
-                request_port: match connection.get::<_, String>("request_port")?.parse::<u16>() {
-                    Ok(n) => n,
-                    Err(_) => DEFAULT_REQUEST,
-                },
-                workers: match connection.get::<_, String>("workers")?.parse::<u32>() {
-                    Ok(n) => n,
-                    Err(_) => DEFAULT_WORKERS,
+                subscribe_port: r.get(&connection, &path, "subscribe_port", DEFAULT_PUBLISH)?,
+                request_port: r.get(&connection, &path, "request_port", DEFAULT_REQUEST)?,
+                workers,
+                auto_workers,
+                use_ipv4: r.get(&connection, &path, "use_ipv4", false)?,
+                schedule: r.get(&connection, &path, "schedule", schedule.clone())?,
+                // empty is not recording
+                record: match r.string(&connection, &path, "record", "")? {
+                    p if p.is_empty() => p,
+                    p => data_path(&data_directory, p),
                 },
-                use_ipv4: match logging.get::<_, bool>("use_ipv4") {
-                    Ok(n) => n,
-                    Err(_) => false,
+                dry_run: r.get(&connection, &path, "dry_run", false)?,
+                interleave,
+                argon2: match connection.get::<_, Option<Table>>("argon2")? {
+                    Some(t) => {
+                        let path = key_path(&path, "argon2");
+                        let d = Argon2::default();
+                        Argon2 {
+                            variant: r.string(&t, &path, "variant", &d.variant)?,
+                            version: r.get(&t, &path, "version", d.version)?,
+                            memory: r.get(&t, &path, "memory", d.memory)?,
+                            iterations: r.get(&t, &path, "iterations", d.iterations)?,
+                            lanes: r.get(&t, &path, "lanes", d.lanes)?,
+                        }
+                    }
+                    None => {
+                        r.defaults.push(key_path(&path, "argon2"));
+                        Argon2::default()
+                    }
                 },
             };
             cn.push(c);
         }
 
+        let level = r.string(&logging, "logging", "level", DEFAULT_LOG_LEVEL)?;
         let lg = Logging {
-            directory: {
-                let mut d = logging
-                    .get::<_, String>("data_directory")
-                    .unwrap_or(DEFAULT_LOG_DIRECTORY.to_string())
-                    .trim()
-                    .to_string();
-                if d.is_empty() {
-                    d = DEFAULT_LOG_DIRECTORY.to_string()
-                }
-                if d.starts_with("/") {
-                    d
-                } else {
-                    data_directory.clone() + "/" + d.as_str()
-                }
-            },
-            file: match logging.get::<_, String>("file") {
-                Ok(s) => s.trim().to_string(),
-                Err(_) => DEFAULT_LOG_FILE.to_string(),
-            },
-            size: match logging.get::<_, String>("size")?.parse::<u64>() {
-                Ok(n) => n,
-                Err(_) => DEFAULT_LOG_SIZE,
-            },
-            count: match logging.get::<_, String>("count")?.parse::<u32>() {
-                Ok(n) => n,
-                Err(_) => DEFAULT_LOG_COUNT,
-            },
-            console: match logging.get::<_, bool>("console") {
-                Ok(n) => n,
-                Err(_) => false,
-            },
-            level: logging.get("level")?,
+            directory: data_path(
+                &data_directory,
+                r.string(&logging, "logging", "data_directory", DEFAULT_LOG_DIRECTORY)?,
+            ),
+            file: r.string(&logging, "logging", "file", DEFAULT_LOG_FILE)?,
+            size: r.get(&logging, "logging", "size", DEFAULT_LOG_SIZE)?,
+            count: r.get(&logging, "logging", "count", DEFAULT_LOG_COUNT)?,
+            rotate: r.string(&logging, "logging", "rotate", DEFAULT_LOG_ROTATE)?,
+            compress: r.get(&logging, "logging", "compress", false)?,
+            retention_days: r.get(
+                &logging,
+                "logging",
+                "retention_days",
+                DEFAULT_LOG_RETENTION_DAYS,
+            )?,
+            console: r.get(&logging, "logging", "console", false)?,
+            level: level.clone(),
+            format: r.string(&logging, "logging", "format", DEFAULT_LOG_FORMAT)?,
+            pattern: r.get(
+                &logging,
+                "logging",
+                "pattern",
+                DEFAULT_LOG_PATTERN.to_string(),
+            )?,
+            time: r.string(&logging, "logging", "time", DEFAULT_LOG_TIME)?,
+            console_level: r.string(&logging, "logging", "console_level", &level)?,
+            file_level: r.string(&logging, "logging", "file_level", &level)?,
+            console_level_set: logging.contains_key("console_level")?,
+            file_level_set: logging.contains_key("file_level")?,
+            modules: r.get(&logging, "logging", "modules", BTreeMap::new())?,
+            output: r.get(
+                &logging,
+                "logging",
+                "output",
+                vec![DEFAULT_LOG_OUTPUT.to_string()],
+            )?,
+            syslog_address: r.string(
+                &logging,
+                "logging",
+                "syslog_address",
+                DEFAULT_SYSLOG_ADDRESS,
+            )?,
+            syslog_facility: r.string(
+                &logging,
+                "logging",
+                "syslog_facility",
+                DEFAULT_SYSLOG_FACILITY,
+            )?,
         };
 
+        let mut levels = vec![
+            ("logging.level".to_string(), &lg.level),
+            ("logging.console_level".to_string(), &lg.console_level),
+            ("logging.file_level".to_string(), &lg.file_level),
+        ];
+        for (module, level) in &lg.modules {
+            levels.push((format!("logging.modules.{}", module), level));
+        }
+        for (key, level) in levels {
+            if !LOG_LEVELS.contains(&level.as_str()) {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "{}: invalid log level: {} expected one of: {}",
+                    key,
+                    level,
+                    LOG_LEVELS.join(", ")
+                )));
+            }
+        }
+        if !LOG_TIMES.contains(&lg.time.as_str()) {
+            return Err(rlua::Error::RuntimeError(format!(
+                "logging.time: invalid value: {} expected one of: {}",
+                lg.time,
+                LOG_TIMES.join(", ")
+            )));
+        }
+
+        let throttle: Table = match config.get::<_, Option<Table>>("throttle")? {
+            Some(t) => t,
+            None => lua.create_table()?,
+        };
+        let th = Throttle {
+            enable: r.get(&throttle, "throttle", "enable", false)?,
+            mode: r.string(&throttle, "throttle", "mode", DEFAULT_THROTTLE_MODE)?,
+            interval: r.get(&throttle, "throttle", "interval", DEFAULT_THROTTLE_INTERVAL)?,
+            load: r.get(&throttle, "throttle", "load", 0.0)?,
+            memory_pressure: r.get(&throttle, "throttle", "memory_pressure", 0.0)?,
+            temperature: r.get(&throttle, "throttle", "temperature", 0.0)?,
+            loadavg_path: r.string(&throttle, "throttle", "loadavg_path", DEFAULT_LOADAVG_PATH)?,
+            pressure_path: r.string(
+                &throttle,
+                "throttle",
+                "pressure_path",
+                DEFAULT_PRESSURE_PATH,
+            )?,
+            thermal_path: r.string(&throttle, "throttle", "thermal_path", DEFAULT_THERMAL_PATH)?,
+        };
+
+        let keys = hooks::load(lua, &config)?;
+
         let result = Configuration {
-            data_directory: data_directory,
+            data_directory,
+            daemon,
+            pidfile,
+            control_socket,
+            journal,
+            user,
+            group,
+            memory_check,
+            stall_seconds,
+            verify_digest,
+            self_test_interval,
+            argon2_backend,
             connections: cn,
             logging: lg,
+            throttle: th,
+            hooks: Arc::default(),
+            defaults: r.defaults,
         };
-        Ok(result)
+        Ok((result, keys))
     })?;
 
+    result.hooks = Arc::new(hooks::Hooks::new(lua, keys));
+
     Ok(result)
 }
+
+// fetch values from Lua tables recording which ones were defaulted
+#[derive(Default)]
+struct Reader {
+    defaults: Vec<String>,
+}
+
+impl Reader {
+    fn get<'lua, T: FromLua<'lua>>(
+        &mut self,
+        table: &Table<'lua>,
+        path: &str,
+        key: &str,
+        default: T,
+    ) -> Result<T> {
+        match table.get::<_, Option<T>>(key)? {
+            Some(value) => Ok(value),
+            None => {
+                self.defaults.push(key_path(path, key));
+                Ok(default)
+            }
+        }
+    }
+
+    // strings are trimmed and empty is the same as missing
+    fn string(&mut self, table: &Table, path: &str, key: &str, default: &str) -> Result<String> {
+        match table.get::<_, Option<String>>(key)? {
+            Some(s) if !s.trim().is_empty() => Ok(s.trim().to_string()),
+            _ => {
+                self.defaults.push(key_path(path, key));
+                Ok(default.to_string())
+            }
+        }
+    }
+}
+
+fn key_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+// connection number in the form: C1 or 1
+pub fn parse_connection(s: &str) -> std::result::Result<i64, String> {
+    let n = s.trim();
+    let n = n.strip_prefix(['C', 'c']).unwrap_or(n);
+    match n.parse::<i64>() {
+        Ok(n) if n >= 1 => Ok(n),
+        _ => Err(format!("invalid connection: {}", s)),
+    }
+}
+
+// a path relative to data_directory unless absolute
+fn data_path(data_directory: &str, value: String) -> String {
+    if value.starts_with('/') {
+        value
+    } else {
+        format!("{}/{}", data_directory, value)
+    }
+}
+
+// worker count for a connection in the form: C1=4
+pub fn parse_workers(s: &str) -> std::result::Result<(i64, u32), String> {
+    let (c, w) = match s.split_once('=') {
+        Some(cw) => cw,
+        None => return Err(format!("expected CONNECTION=WORKERS, not: {}", s)),
+    };
+    let w = match w.trim().parse::<u32>() {
+        Ok(w) => w,
+        Err(_) => return Err(format!("invalid workers: {}", s)),
+    };
+    Ok((parse_connection(c)?, w))
+}
+
+// worker counts from the environment then the command line, so the
+// command line replaces only the connections it names
+pub fn merge_workers(
+    env: Option<&str>,
+    args: &[(i64, u32)],
+) -> std::result::Result<Vec<(i64, u32)>, String> {
+    let mut workers = Vec::new();
+    for s in env
+        .unwrap_or("")
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+    {
+        workers.push(parse_workers(s)?);
+    }
+    for w in args {
+        workers.retain(|(n, _)| *n != w.0);
+        workers.push(*w);
+    }
+    Ok(workers)
+}
+
+// connections to enable and disable from the environment then the
+// command line, so the command line decides for the connections it
+// names; naming one both ways in the same place is an error
+pub fn merge_enable(
+    env_enable: Option<&str>,
+    env_disable: Option<&str>,
+    enable: &[i64],
+    disable: &[i64],
+) -> std::result::Result<(Vec<i64>, Vec<i64>), String> {
+    let list = |s: Option<&str>| {
+        s.unwrap_or("")
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(parse_connection)
+            .collect::<std::result::Result<Vec<i64>, String>>()
+    };
+    let both = |enable: &[i64], disable: &[i64]| match enable.iter().find(|n| disable.contains(n)) {
+        Some(n) => Err(format!("C{}: both enabled and disabled", n)),
+        None => Ok(()),
+    };
+    let (mut enabled, mut disabled) = (list(env_enable)?, list(env_disable)?);
+    both(&enabled, &disabled)?;
+    both(enable, disable)?;
+    enabled.retain(|n| !disable.contains(n));
+    disabled.retain(|n| !enable.contains(n));
+    for (list, given) in [(&mut enabled, enable), (&mut disabled, disable)] {
+        for n in given {
+            if !list.contains(n) {
+                list.push(*n);
+            }
+        }
+    }
+    Ok((enabled, disabled))
+}
+
+pub fn apply_overrides(cfg: &mut Configuration, overrides: &Overrides) -> MyResult<()> {
+    if let Some(n) = overrides
+        .enable
+        .iter()
+        .find(|n| overrides.disable.contains(n))
+    {
+        bail!("C{}: both enabled and disabled", n);
+    }
+    if let Some(level) = &overrides.log_level {
+        let level = level.trim().to_lowercase();
+        if !LOG_LEVELS.contains(&level.as_str()) {
+            bail!("invalid log level: {}", level);
+        }
+        // the appender levels follow unless they were set explicitly
+        for (key, value) in [
+            ("logging.console_level", &mut cfg.logging.console_level),
+            ("logging.file_level", &mut cfg.logging.file_level),
+        ] {
+            if cfg.defaults.iter().any(|p| p == key) {
+                *value = level.clone();
+            }
+        }
+        cfg.logging.level = level;
+        overridden(cfg, "logging.level".to_string());
+    }
+    if let Some(console) = overrides.log_console {
+        cfg.logging.console = console;
+        overridden(cfg, "logging.console".to_string());
+    }
+    if let Some(daemon) = overrides.daemon {
+        cfg.daemon = daemon;
+        overridden(cfg, "daemon".to_string());
+    }
+    if overrides.dry_run {
+        for n in 1..=cfg.connections.len() {
+            cfg.connections[n - 1].dry_run = true;
+            overridden(cfg, format!("connections[{}].dry_run", n));
+        }
+    }
+
+    for (n, workers) in &overrides.workers {
+        let c = connection_mut(cfg, *n)?;
+        c.workers = *workers;
+        c.auto_workers = false;
+        overridden(cfg, format!("connections[{}].workers", n));
+    }
+    for n in &overrides.enable {
+        connection_mut(cfg, *n)?.enable = true;
+        overridden(cfg, format!("connections[{}].enable", n));
+    }
+    for n in &overrides.disable {
+        connection_mut(cfg, *n)?.enable = false;
+        overridden(cfg, format!("connections[{}].enable", n));
+    }
+
+    Ok(())
+}
+
+fn overridden(cfg: &mut Configuration, path: String) {
+    cfg.defaults.retain(|p| *p != path);
+}
+
+fn connection_mut(cfg: &mut Configuration, n: i64) -> MyResult<&mut Connection> {
+    match cfg.connections.iter_mut().find(|c| c.number == n) {
+        Some(c) => Ok(c),
+        None => bail!("connection: C{} is not configured", n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logging_values() {
+        let path =
+            std::env::temp_dir().join(format!("mt-recorder-logging-{}.conf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let read = |logging: &str| {
+            std::fs::write(
+                path,
+                format!(
+                    "return {{ connections = {{}}, logging = {{ {} }} }}",
+                    logging
+                ),
+            )
+            .unwrap();
+            read(path, false)
+        };
+        assert!(read(r#"time = "local", level = "debug""#).is_ok());
+        assert!(read(r#"time = "gmt""#).is_err());
+        assert!(read(r#"modules = { responder = "loud" }"#).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_overrides() {
+        assert_eq!(parse_connection("C2"), Ok(2));
+        assert_eq!(parse_connection("3"), Ok(3));
+        assert!(parse_connection("C0").is_err());
+        assert_eq!(parse_workers("C1=4"), Ok((1, 4)));
+        assert!(parse_workers("C1").is_err());
+        assert_eq!(
+            merge_workers(Some("C1=4,C2=2"), &[(2, 6), (3, 1)]),
+            Ok(vec![(1, 4), (2, 6), (3, 1)])
+        );
+        assert_eq!(merge_workers(None, &[(1, 2)]), Ok(vec![(1, 2)]));
+        assert!(merge_workers(Some("C1"), &[]).is_err());
+
+        // the command line decides for the connections it names
+        assert_eq!(
+            merge_enable(Some("C1,C2"), Some("C3"), &[], &[1]),
+            Ok((vec![2], vec![3, 1]))
+        );
+        assert_eq!(
+            merge_enable(None, Some("C3"), &[3], &[]),
+            Ok((vec![3], vec![]))
+        );
+        assert!(merge_enable(Some("C1"), Some("C1"), &[], &[]).is_err());
+        assert!(merge_enable(None, None, &[2], &[2]).is_err());
+
+        let connection = Connection {
+            number: 1,
+            enable: true,
+            workers: 1,
+            auto_workers: true,
+            use_ipv4: false,
+            host: "127.0.0.1".to_string(),
+            public_key: "".to_string(),
+            subscribe_port: DEFAULT_PUBLISH,
+            request_port: DEFAULT_REQUEST,
+            schedule: Vec::new(),
+            record: "".to_string(),
+            dry_run: false,
+            interleave: DEFAULT_INTERLEAVE,
+            argon2: Argon2::default(),
+        };
+        let mut cfg = Configuration {
+            data_directory: DEFAULT_DATA_DIRECTORY.to_string(),
+            daemon: false,
+            pidfile: DEFAULT_PIDFILE.to_string(),
+            control_socket: DEFAULT_CONTROL_SOCKET.to_string(),
+            journal: DEFAULT_JOURNAL.to_string(),
+            user: "".to_string(),
+            group: "".to_string(),
+            memory_check: DEFAULT_MEMORY_CHECK.to_string(),
+            stall_seconds: DEFAULT_STALL_SECONDS,
+            verify_digest: false,
+            self_test_interval: DEFAULT_SELF_TEST_INTERVAL,
+            argon2_backend: DEFAULT_ARGON2_BACKEND.to_string(),
+            connections: vec![connection],
+            logging: Logging {
+                directory: DEFAULT_LOG_DIRECTORY.to_string(),
+                file: DEFAULT_LOG_FILE.to_string(),
+                size: DEFAULT_LOG_SIZE,
+                count: DEFAULT_LOG_COUNT,
+                rotate: DEFAULT_LOG_ROTATE.to_string(),
+                compress: false,
+                retention_days: DEFAULT_LOG_RETENTION_DAYS,
+                console: false,
+                level: "warn".to_string(),
+                format: DEFAULT_LOG_FORMAT.to_string(),
+                pattern: DEFAULT_LOG_PATTERN.to_string(),
+                time: DEFAULT_LOG_TIME.to_string(),
+                console_level: "warn".to_string(),
+                file_level: "error".to_string(),
+                console_level_set: false,
+                file_level_set: true,
+                modules: BTreeMap::new(),
+                output: vec![DEFAULT_LOG_OUTPUT.to_string()],
+                syslog_address: DEFAULT_SYSLOG_ADDRESS.to_string(),
+                syslog_facility: DEFAULT_SYSLOG_FACILITY.to_string(),
+            },
+            throttle: Throttle::default(),
+            hooks: Arc::default(),
+            defaults: vec![
+                "connections[1].workers".to_string(),
+                "logging.console_level".to_string(),
+            ],
+        };
+
+        let overrides = Overrides {
+            log_level: Some("Debug".to_string()),
+            daemon: Some(true),
+            dry_run: true,
+            workers: vec![(1, 4)],
+            disable: vec![1],
+            ..Default::default()
+        };
+        apply_overrides(&mut cfg, &overrides).unwrap();
+        assert_eq!(cfg.logging.level, "debug");
+        assert_eq!(cfg.logging.console_level, "debug");
+        assert_eq!(cfg.logging.file_level, "error");
+        assert_eq!(cfg.connections[0].workers, 4);
+        assert!(!cfg.connections[0].auto_workers);
+        assert!(!cfg.connections[0].enable);
+        assert!(cfg.daemon);
+        assert!(cfg.connections[0].dry_run);
+        assert_eq!(cfg.defaults, vec!["logging.console_level"]);
+
+        let overrides = Overrides {
+            enable: vec![2],
+            ..Default::default()
+        };
+        assert!(apply_overrides(&mut cfg, &overrides).is_err());
+
+        let overrides = Overrides {
+            enable: vec![1],
+            disable: vec![1],
+            ..Default::default()
+        };
+        assert!(apply_overrides(&mut cfg, &overrides).is_err());
+    }
+}
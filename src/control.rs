@@ -0,0 +1,249 @@
+// control.rs
+
+// local control socket: one command line per connection, answered with
+// text lines, the reply to a failed command starts with "error:"
+
+use simple_error::bail;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::config;
+use super::logging;
+use super::resources;
+use super::statistics;
+use super::throttle;
+use super::worker;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const HELP: &str = "commands:
+  status
+  pause C<n>
+  resume C<n>
+  workers C<n> <count>
+  loglevel <level>
+  reconnect C<n>
+";
+
+// the running parts of a connection
+pub struct Connection {
+    pub stats: Arc<statistics::Connection>,
+    pub pool: Arc<Mutex<worker::Pool>>,
+}
+
+struct Server {
+    connections: Vec<Connection>,
+    logging: config::Logging,
+    handle: log4rs::Handle,
+    throttle: Arc<throttle::Throttle>,
+    // as at startup, for workers added while running
+    memory_check: String,
+    limits: resources::Limits,
+}
+
+// the lock on the pidfile is already held, so an existing socket is
+// left over from a previous run
+pub fn spawn(
+    path: &str,
+    connections: Vec<Connection>,
+    logging: config::Logging,
+    handle: log4rs::Handle,
+    throttle: Arc<throttle::Throttle>,
+    memory_check: String,
+    limits: resources::Limits,
+) -> MyResult<()> {
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    log::info!("control socket: {}", path);
+
+    let mut server = Server {
+        connections,
+        logging,
+        handle,
+        throttle,
+        memory_check,
+        limits,
+    };
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = server.serve(stream) {
+                        log::error!("control: {}", e);
+                    }
+                }
+                Err(e) => log::error!("control: accept error: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Server {
+    fn serve(&mut self, stream: UnixStream) -> MyResult<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        log::info!("control: {}", line.trim());
+
+        let reply = match self.command(&line) {
+            Ok(reply) => reply,
+            Err(e) => format!("error: {}\n", e),
+        };
+        (&stream).write_all(reply.as_bytes())?;
+        Ok(())
+    }
+
+    fn command(&mut self, line: &str) -> MyResult<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["status"] => Ok(self.status()),
+            ["pause", c] => {
+                self.connection(c)?
+                    .stats
+                    .paused
+                    .store(true, Ordering::Relaxed);
+                log::warn!("control: C{}: paused", self.connection(c)?.stats.number);
+                Ok("paused\n".to_string())
+            }
+            ["resume", c] => {
+                self.connection(c)?
+                    .stats
+                    .paused
+                    .store(false, Ordering::Relaxed);
+                log::warn!("control: C{}: resumed", self.connection(c)?.stats.number);
+                Ok("resumed\n".to_string())
+            }
+            ["workers", c, n] => {
+                let n = match n.parse::<u32>() {
+                    Ok(n) if n > 0 => n,
+                    _ => bail!("invalid workers: {}, pause the connection instead of 0", n),
+                };
+                let connection = self.connection(c)?;
+                let warning = self.check_memory(connection.stats.number, n)?;
+                connection.pool.lock().unwrap().resize(n)?;
+                match warning {
+                    Some(warning) => {
+                        log::warn!("control: {}", warning);
+                        Ok(format!("workers: {}\nwarning: {}\n", n, warning))
+                    }
+                    None => Ok(format!("workers: {}\n", n)),
+                }
+            }
+            ["loglevel", level] => {
+                let level = level.to_lowercase();
+                if level.parse::<log::LevelFilter>().is_err() {
+                    bail!("invalid log level: {}", level);
+                }
+                // applies to all appenders, module levels are kept
+                self.logging.level = level.clone();
+                self.logging.console_level = level.clone();
+                self.logging.file_level = level.clone();
+                self.handle.set_config(logging::build(&self.logging)?);
+                log::warn!("control: log level: {}", level);
+                Ok(format!("loglevel: {}\n", level))
+            }
+            ["reconnect", c] => {
+                let stats = &self.connection(c)?.stats;
+                stats.reconnect_subscribe.store(true, Ordering::Relaxed);
+                stats.reconnect_request.store(true, Ordering::Relaxed);
+                log::warn!("control: C{}: reconnect", stats.number);
+                Ok("reconnecting\n".to_string())
+            }
+            ["help"] => Ok(HELP.to_string()),
+            [] => bail!("empty command"),
+            _ => bail!("unknown command: {}", line.trim()),
+        }
+    }
+
+    fn connection(&self, c: &str) -> MyResult<&Connection> {
+        let n = config::parse_connection(c)?;
+        match self.connections.iter().find(|x| x.stats.number == n) {
+            Some(x) => Ok(x),
+            None => bail!("connection: C{} is not running", n),
+        }
+    }
+
+    // all the running workers, with count for one connection, checked
+    // the same as at startup
+    fn check_memory(&self, number: i64, count: u32) -> MyResult<Option<String>> {
+        let workers: Vec<(u32, u64)> = self
+            .connections
+            .iter()
+            .map(|c| {
+                let pool = c.pool.lock().unwrap();
+                match c.stats.number == number {
+                    true => (count, pool.worker_memory()),
+                    false => (pool.len() as u32, pool.worker_memory()),
+                }
+            })
+            .collect();
+        resources::check(&workers, &self.memory_check, &self.limits)
+    }
+
+    fn status(&self) -> String {
+        let mut s = String::new();
+        for c in &self.connections {
+            let stats = &c.stats;
+            s += &format!(
+                "C{}: {}{}{}  workers: {}/{}  restarts: {}  jobs: {}  hashes: {}  found: {}  replies: {}  hardware errors: {}  self test failures: {}\n",
+                stats.number,
+                if stats.connected() {
+                    "connected"
+                } else {
+                    "disconnected"
+                },
+                if stats.paused.load(Ordering::Relaxed) {
+                    "  paused"
+                } else {
+                    ""
+                },
+                if stats.off_schedule.load(Ordering::Relaxed) {
+                    "  off schedule"
+                } else {
+                    ""
+                },
+                stats.workers_alive(),
+                c.pool.lock().unwrap().len(),
+                stats.restarts.load(Ordering::Relaxed),
+                stats.jobs.load(Ordering::Relaxed),
+                stats.hashes.load(Ordering::Relaxed),
+                stats.found.load(Ordering::Relaxed),
+                stats.replies.load(Ordering::Relaxed),
+                stats.hardware_errors.load(Ordering::Relaxed),
+                stats.self_test_failures.load(Ordering::Relaxed),
+            );
+        }
+        if self.throttle.enabled() {
+            s += &format!("throttle: {}%\n", self.throttle.level() * 10);
+        }
+        s
+    }
+}
+
+// the ctl subcommand: send one command and return the reply
+pub fn send(path: &str, command: &[String]) -> MyResult<String> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) => bail!("control socket: {} error: {}", path, e),
+    };
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    writeln!(stream, "{}", command.join(" "))?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    if let Some(e) = reply.strip_prefix("error: ") {
+        bail!("{}", e.trim());
+    }
+    Ok(reply)
+}
@@ -0,0 +1,191 @@
+// daemon.rs
+
+// background operation: single instance lock, detaching from the
+// terminal and running as an unprivileged user
+
+use simple_error::bail;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// the open pid file holding an exclusive lock, the kernel releases the
+// lock when the process exits so a left over file is harmless
+#[derive(Debug)]
+pub struct Pidfile(File);
+
+impl Pidfile {
+    pub fn lock(path: &str) -> MyResult<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                bail!(
+                    "{}: in use by another mt-recorder pid: {}",
+                    path,
+                    pid.trim()
+                );
+            }
+            bail!("{}: lock error: {}", path, e);
+        }
+        Ok(Pidfile(file))
+    }
+
+    // record the current process, the pid changes when detaching
+    pub fn write_pid(&mut self) -> MyResult<()> {
+        self.0.set_len(0)?;
+        self.0.rewind()?;
+        writeln!(self.0, "{}", std::process::id())?;
+        Ok(())
+    }
+}
+
+// double fork: the first parent returns to the shell, setsid drops the
+// controlling terminal and the second fork means one cannot be acquired
+// again; must be called before any threads are started
+//
+// the working directory is kept since data_directory may be relative
+pub fn detach() -> MyResult<()> {
+    fork_and_exit()?;
+    if unsafe { libc::setsid() } == -1 {
+        bail!("setsid error: {}", std::io::Error::last_os_error());
+    }
+    fork_and_exit()?;
+
+    unsafe { libc::umask(0o027) };
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            bail!("dup2 error: {}", std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// only the child returns
+fn fork_and_exit() -> MyResult<()> {
+    match unsafe { libc::fork() } {
+        -1 => bail!("fork error: {}", std::io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+// switch to the configured user and group, an empty group means the
+// primary group of the user
+pub fn drop_privileges(user: &str, group: &str) -> MyResult<()> {
+    if user.is_empty() && group.is_empty() {
+        return Ok(());
+    }
+
+    let (uid, primary_gid) = if user.is_empty() {
+        (None, None)
+    } else {
+        let (uid, gid) = lookup_user(user)?;
+        (Some(uid), Some(gid))
+    };
+    let gid = if group.is_empty() {
+        primary_gid
+    } else {
+        Some(lookup_group(group)?)
+    };
+
+    if let Some(gid) = gid {
+        // only root may clear the supplementary groups
+        if unsafe { libc::geteuid() } == 0 && unsafe { libc::setgroups(1, &gid) } != 0 {
+            bail!("setgroups error: {}", std::io::Error::last_os_error());
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            bail!(
+                "cannot change to group: {} error: {}",
+                gid,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            bail!(
+                "cannot change to user: {} error: {}",
+                user,
+                std::io::Error::last_os_error()
+            );
+        }
+        // root must not be recoverable
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            bail!("privileges were not dropped for user: {}", user);
+        }
+    }
+
+    log::info!(
+        "running as uid: {} gid: {}",
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    Ok(())
+}
+
+// getpwnam/getgrnam are not thread safe, this runs before the
+// connection threads are started
+fn lookup_user(name: &str) -> MyResult<(libc::uid_t, libc::gid_t)> {
+    let c = CString::new(name)?;
+    let pw = unsafe { libc::getpwnam(c.as_ptr()) };
+    if pw.is_null() {
+        bail!("unknown user: {}", name);
+    }
+    Ok(unsafe { ((*pw).pw_uid, (*pw).pw_gid) })
+}
+
+fn lookup_group(name: &str) -> MyResult<libc::gid_t> {
+    let c = CString::new(name)?;
+    let gr = unsafe { libc::getgrnam(c.as_ptr()) };
+    if gr.is_null() {
+        bail!("unknown group: {}", name);
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pidfile() {
+        let path =
+            std::env::temp_dir().join(format!("mt-recorder-test-{}.pid", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut first = Pidfile::lock(path).unwrap();
+        first.write_pid().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        // a separate open file description conflicts with the lock
+        let e = Pidfile::lock(path).unwrap_err().to_string();
+        assert!(e.contains(&std::process::id().to_string()), "{}", e);
+
+        drop(first);
+        assert!(Pidfile::lock(path).is_ok());
+        std::fs::remove_file(path).unwrap();
+
+        assert!(lookup_user("root").is_ok());
+        assert!(lookup_group("no-such-group-mt-recorder").is_err());
+    }
+}
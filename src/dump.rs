@@ -0,0 +1,502 @@
+// dump.rs
+
+use serde_json::Value;
+use std::fmt::Write;
+
+use super::block;
+use super::config;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    Json,
+    Lua,
+}
+
+// render the fully resolved configuration
+pub fn configuration(cfg: &config::Configuration, format: Format) -> MyResult<String> {
+    match format {
+        Format::Json => Ok(serde_json::to_string_pretty(cfg)? + "\n"),
+        Format::Lua => {
+            let value = serde_json::to_value(cfg)?;
+            let mut out = String::new();
+            writeln!(out, "-- mt-recorder effective configuration")?;
+            writeln!(out, "-- values marked 'default' were not set in the file")?;
+            writeln!(out, "-- hooks: {}", cfg.hooks.names().join(", "))?;
+            writeln!(out)?;
+            write!(out, "return ")?;
+            lua_value(&mut out, &value, "", 0, &cfg.defaults)?;
+            writeln!(out)?;
+            Ok(out)
+        }
+    }
+}
+
+fn lua_value(
+    out: &mut String,
+    value: &Value,
+    path: &str,
+    indent: usize,
+    defaults: &[String],
+) -> std::fmt::Result {
+    let pad = "    ".repeat(indent + 1);
+    match value {
+        Value::Object(map) => {
+            writeln!(out, "{{")?;
+            for (k, v) in map {
+                // not configuration values
+                if path.is_empty() && (k == "hooks" || k == "defaults") {
+                    continue;
+                }
+                let p = if path.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", path, k)
+                };
+                if is_name(k) {
+                    write!(out, "{}{} = ", pad, k)?;
+                } else {
+                    write!(out, "{}[{}] = ", pad, lua_string(k))?;
+                }
+                lua_value(out, v, &p, indent + 1, defaults)?;
+                write!(out, ",")?;
+                if defaults.contains(&p) {
+                    write!(out, " -- default")?;
+                }
+                writeln!(out)?;
+            }
+            write!(out, "{}}}", "    ".repeat(indent))
+        }
+        Value::Array(items) => {
+            writeln!(out, "{{")?;
+            for (i, v) in items.iter().enumerate() {
+                write!(out, "{}", pad)?;
+                lua_value(
+                    out,
+                    v,
+                    &format!("{}[{}]", path, i + 1),
+                    indent + 1,
+                    defaults,
+                )?;
+                writeln!(out, ",")?;
+            }
+            write!(out, "{}}}", "    ".repeat(indent))
+        }
+        Value::String(s) => write!(out, "{}", lua_string(s)),
+        Value::Null => write!(out, "nil"),
+        v => write!(out, "{}", v),
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+fn lua_string(s: &str) -> String {
+    let mut q = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => q.push_str("\\\""),
+            '\\' => q.push_str("\\\\"),
+            '\n' => q.push_str("\\n"),
+            '\r' => q.push_str("\\r"),
+            '\t' => q.push_str("\\t"),
+            c if c.is_control() => q.push_str(&format!("\\{:03}", c as u32)),
+            c => q.push(c),
+        }
+    }
+    q.push('"');
+    q
+}
+
+// a commented sample configuration using the current defaults, values
+// that differ are marked as examples
+pub fn sample() -> String {
+    format!(
+        r#"-- mt-recorder.conf  -*- mode: lua -*-
+
+local M = {{}}
+
+-- Read the named file in under the specified data directory
+-- M.data_directory and return the contents.
+function read_file(name)
+    local f, err = io.open(M.data_directory .. "/" .. name, "r")
+    if f == nil then
+        return ""
+    end
+    local r = f:read("*a")
+    f:close()
+    return r
+end
+
+-- set the directory for data and log files (default: "{data_directory}")
+--M.data_directory = arg[0]:match("(.*/)")     -- dir from configuration file
+--M.data_directory = "."                       -- current directory
+M.data_directory = "/var/lib/mt-recorder"    -- absolute path
+
+-- set to true to detach and run in the background
+M.daemon = false
+
+-- process id file, relative to data_directory; it is locked while
+-- running so only one mt-recorder can use the data directory
+M.pidfile = "{pidfile}"
+
+-- control socket, relative to data_directory, for the ctl subcommand:
+--   mt-recorder --config mt-recorder.conf ctl status
+--   commands: status, pause C1, resume C1, workers C1 6,
+--             loglevel debug, reconnect C2
+M.control_socket = "{control_socket}"
+
+-- record of found nonces and replies, relative to data_directory:
+--   mt-recorder --config mt-recorder.conf journal --by day
+M.journal = "{journal}"
+
+-- user and group to run as once the keys have been read and the log
+-- files opened; group defaults to the primary group of the user
+--M.user = "mt-recorder"
+--M.group = "mt-recorder"
+
+-- hashing windows in local time, hashing is allowed while any rule
+-- matches and at all times when there are no rules; a connection can
+-- have its own schedule to replace this one
+--   "mon-fri 18:00-08:00"  the listed days and a time of day range,
+--                          here until saturday 08:00
+--   "sat,sun"              the whole of the listed days
+--   "* 0-7,18-23 * * 1-5"  cron style: minute hour day month weekday
+--M.schedule = {{ "mon-fri 18:00-08:00", "sat,sun" }}
+
+-- each worker needs 128 MiB, or its connection's argon2 memory; when
+-- the enabled workers need more memory than is available: "refuse" to
+-- start, "warn" and start, or "off"
+M.memory_check = "{memory_check}"
+
+-- workers that die or make no hash progress for this many seconds are
+-- restarted on the current job, 0 only restarts workers that die;
+-- interleaved workers are allowed this much for each digest of a batch
+M.stall_seconds = {stall_seconds}
+
+-- recompute the digest of each found nonce with a separate plain
+-- Argon2 implementation before submitting it, a mismatch is counted as
+-- a hardware error and the nonce is dropped; it takes about a second
+-- for each nonce found and, run between batches, no memory beyond the
+-- worker's own
+M.verify_digest = false
+
+-- the hashing is checked against known digests at startup, which must
+-- pass, and by each worker every this many seconds, a worker that
+-- fails stops and is restarted by the supervisor until a connection
+-- has failed 3 times, then its workers stay stopped and the systemd
+-- watchdog is no longer fed; 0 checks only at startup
+M.self_test_interval = {self_test_interval}
+
+-- Argon2 implementation, "auto" picks the fastest this CPU supports;
+-- "avx2", "sse4.1", "portable" or "library" force one, for
+-- troubleshooting
+M.argon2_backend = "{argon2_backend}"
+
+-- connection to bitmarkd
+M.connections = {{
+
+    {{
+        enable = false,
+
+        -- number of hashing threads (default: {workers}), or "auto" to share
+        -- the CPUs and memory available between the connections set to
+        -- "auto"; 2 is an example
+        workers = 2,
+
+        -- only hash at weekends
+        --schedule = {{ "sat,sun" }},
+
+        -- append every job received to this file in data_directory:
+        --   mt-recorder --config mt-recorder.conf replay jobs-C1.jsonl
+        --record = "jobs-C1.jsonl",
+
+        -- block digest parameters, only for private test chains; these
+        -- are the bitmarkd values, memory is in KiB per worker
+        --argon2 = {{
+        --    variant = "{argon2_variant}",
+        --    version = 0x{argon2_version:x},
+        --    memory = {argon2_memory},
+        --    iterations = {argon2_iterations},
+        --    lanes = {argon2_lanes},
+        --}},
+
+        -- hash but log and journal found nonces instead of submitting
+        dry_run = false,
+
+        -- digests each worker computes together on consecutive nonces,
+        -- overlapping their memory accesses; each needs its own Argon2
+        -- memory, see the benchmark subcommand for whether this beats
+        -- more workers on a host; only argon2d version 0x13 with a
+        -- backend other than "library" can interleave
+        --interleave = 2,
+
+        -- set if using ipv4 instead of ipv6
+        use_ipv4 = false,
+
+        -- bitmarkd parameters
+        host = "node-d4.live.bitmark.com",
+        public_key = "PUBLIC:cf09b24ce5bf5a00538ba8a63a7d4bbd211e833b00483346ef9d88f4756cb50b",
+
+        -- ports
+        subscribe_port = {subscribe_port},
+        request_port = {request_port},
+    }},
+
+    {{
+        enable = false,
+
+        -- number of hashing threads (default: {workers}), or "auto"
+        workers = 2,
+
+        -- hash but log and journal found nonces instead of submitting
+        dry_run = false,
+
+        -- set if using ipv4 instead of ipv6
+        use_ipv4 = false,
+
+        -- bitmarkd parameters
+        host = "node-d4.test.bitmark.com",
+        public_key = "PUBLIC:2248344e51fd2c13701f0dbf8a27b44a921679cc24897b297522bfd010753f4d",
+
+        -- ports
+        subscribe_port = 12138,
+        request_port = 12139,
+    }},
+
+    {{
+        enable = true,
+
+        -- number of hashing threads (default: {workers}), or "auto"
+        workers = 2,
+
+        -- hash but log and journal found nonces instead of submitting
+        dry_run = false,
+
+        -- set if using ipv4 instead of ipv6
+        use_ipv4 = false,
+
+        -- bitmarkd parameters
+        host = "127.0.0.1",
+        public_key = read_file("../bitmarkd/proof.public"),
+
+        -- ports
+        subscribe_port = 22138,
+        request_port = 22139,
+    }},
+
+}}
+
+
+-- configure global or specific logger channel levels
+M.logging = {{
+
+    -- directory for log files, relative to data_directory
+    data_directory = "{log_directory}",
+
+    -- log file name
+    file = "{log_file}",
+
+    -- log file size and rotation count
+    size = {log_size},
+    count = {log_count},
+
+    -- when to rotate: "size", "hourly" or "daily"
+    rotate = "{log_rotate}",
+
+    -- gzip the rotated files
+    compress = false,
+
+    -- delete rotated files older than this many days, 0 keeps
+    -- the files limited only by count
+    retention_days = {log_retention_days},
+
+    -- set to true to log to console
+    --console = true,
+    console = false,
+
+    -- set log level to output
+    -- "off" gives no output and other levels the set maximum level to output
+    -- "trace" will output everything
+    --level = "off",
+    --level = "error",
+    level = "{log_level}",
+    --level = "info",
+    --level = "debug",
+    --level = "trace",
+
+    -- separate maximum levels for the console and the log file, when
+    -- set they also cap the module levels below; both default to level
+    --console_level = "info",
+    --file_level = "warn",
+
+    -- levels for individual modules, e.g. to silence the per-hash
+    -- worker output while debugging the connection code
+    --modules = {{
+    --    worker = "warn",
+    --    responder = "debug",
+    --}},
+
+    -- "text" for the pattern below or "json" for one object per line
+    -- with connection, worker, job, block, nonce and hash_rate fields
+    format = "{log_format}",
+
+    -- where to send log records, any of:
+    --   "file"      the rotating log file above
+    --   "journald"  systemd-journald with structured fields
+    --   "syslog"    syslog_address: "unix:/dev/log" or "udp:127.0.0.1:514"
+    output = {{ "{log_output}" }},
+    syslog_address = "{syslog_address}",
+    syslog_facility = "{syslog_facility}",
+
+    -- log4rs pattern for "text" format
+    pattern = "{log_pattern}",
+
+    -- time zone for dates in the pattern: "utc" or "local"
+    time = "{log_time}",
+}}
+
+
+-- slow hashing down when the host is busy, short of memory or hot
+M.throttle = {{
+    enable = false,
+
+    -- "duty" idles every worker for part of the time,
+    -- "workers" stops some of the workers
+    mode = "{throttle_mode}",
+
+    -- seconds between checks, each check changes the throttle by 10%
+    interval = {throttle_interval},
+
+    -- thresholds, 0 is not checked:
+    --   1 minute load average
+    --   percent of time stalled on memory (PSI some avg10)
+    --   hottest thermal zone in °C
+    load = 0,
+    memory_pressure = 0,
+    temperature = 0,
+
+    -- where the readings come from
+    --loadavg_path = "{loadavg_path}",
+    --pressure_path = "{pressure_path}",
+    --thermal_path = "{thermal_path}",
+}}
+
+
+-- optional event hooks, each is called with a single table argument
+-- returning false from on_job or on_nonce_found drops that job or nonce
+-- nonce is the integer in 16 hex digits, as in the logs and journal
+
+-- t: connection, job, block, version, transaction_count, previous_block,
+--    merkle_root, timestamp, difficulty, tx_ids, workers
+--function M.on_job(t)
+--    return true
+--end
+
+-- t: connection, worker, job, block, nonce, digest
+--function M.on_nonce_found(t)
+--    return true
+--end
+
+-- t: connection, job, nonce, request, reply
+--function M.on_reply(t)
+--end
+
+-- t: connection, socket, address
+--function M.on_disconnect(t)
+--end
+
+
+-- return the complete configuration
+return M
+"#,
+        data_directory = config::DEFAULT_DATA_DIRECTORY,
+        pidfile = config::DEFAULT_PIDFILE,
+        control_socket = config::DEFAULT_CONTROL_SOCKET,
+        journal = config::DEFAULT_JOURNAL,
+        workers = config::DEFAULT_WORKERS,
+        memory_check = config::DEFAULT_MEMORY_CHECK,
+        stall_seconds = config::DEFAULT_STALL_SECONDS,
+        self_test_interval = config::DEFAULT_SELF_TEST_INTERVAL,
+        argon2_backend = config::DEFAULT_ARGON2_BACKEND,
+        argon2_variant = config::DEFAULT_ARGON2_VARIANT,
+        argon2_version = config::DEFAULT_ARGON2_VERSION,
+        argon2_memory = block::DIGEST_MEMORY,
+        argon2_iterations = block::DIGEST_ITERATIONS,
+        argon2_lanes = block::DIGEST_LANES,
+        subscribe_port = config::DEFAULT_PUBLISH,
+        request_port = config::DEFAULT_REQUEST,
+        log_directory = config::DEFAULT_LOG_DIRECTORY,
+        log_file = config::DEFAULT_LOG_FILE,
+        log_size = config::DEFAULT_LOG_SIZE,
+        log_count = config::DEFAULT_LOG_COUNT,
+        log_rotate = config::DEFAULT_LOG_ROTATE,
+        log_retention_days = config::DEFAULT_LOG_RETENTION_DAYS,
+        log_level = config::DEFAULT_LOG_LEVEL,
+        log_format = config::DEFAULT_LOG_FORMAT,
+        log_pattern = config::DEFAULT_LOG_PATTERN,
+        log_time = config::DEFAULT_LOG_TIME,
+        log_output = config::DEFAULT_LOG_OUTPUT,
+        syslog_address = config::DEFAULT_SYSLOG_ADDRESS,
+        syslog_facility = config::DEFAULT_SYSLOG_FACILITY,
+        throttle_mode = config::DEFAULT_THROTTLE_MODE,
+        throttle_interval = config::DEFAULT_THROTTLE_INTERVAL,
+        loadavg_path = config::DEFAULT_LOADAVG_PATH,
+        pressure_path = config::DEFAULT_PRESSURE_PATH,
+        thermal_path = config::DEFAULT_THERMAL_PATH,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample() {
+        // regenerate with: mt-recorder print-sample-config > mt-recorder.conf.sample
+        let filename = concat!(env!("CARGO_MANIFEST_DIR"), "/mt-recorder.conf.sample");
+        assert_eq!(sample(), std::fs::read_to_string(filename).unwrap());
+
+        let cfg = config::read(filename, false).unwrap();
+        assert_eq!(cfg.connections.len(), 3);
+        // only the commented out optional values
+        assert_eq!(
+            cfg.defaults,
+            vec![
+                "user",
+                "group",
+                "schedule",
+                "connections[1].interleave",
+                "connections[1].schedule",
+                "connections[1].record",
+                "connections[1].argon2",
+                "connections[2].interleave",
+                "connections[2].schedule",
+                "connections[2].record",
+                "connections[2].argon2",
+                "connections[3].interleave",
+                "connections[3].schedule",
+                "connections[3].record",
+                "connections[3].argon2",
+                "logging.console_level",
+                "logging.file_level",
+                "logging.modules",
+                "throttle.loadavg_path",
+                "throttle.pressure_path",
+                "throttle.thermal_path"
+            ]
+        );
+
+        let lua = configuration(&cfg, Format::Lua).unwrap();
+        assert!(lua.contains("    data_directory = \"/var/lib/mt-recorder\",\n"));
+        assert!(lua.contains("    file_level = \"warn\", -- default\n"));
+    }
+}
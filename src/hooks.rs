@@ -0,0 +1,159 @@
+// hooks.rs
+
+use rlua::{Context, Function, Lua, RegistryKey, Table, Value};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+// names of the optional callback functions in the configuration table
+const NAMES: [&str; 4] = ["on_job", "on_nonce_found", "on_reply", "on_disconnect"];
+
+pub type Keys = BTreeMap<&'static str, RegistryKey>;
+
+// Lua callbacks returned by the configuration script
+//
+// each hook is called with a single table argument; on_job and
+// on_nonce_found may return false to drop the job or nonce
+#[derive(Default)]
+pub struct Hooks {
+    lua: Option<Mutex<Lua>>,
+    keys: Keys,
+}
+
+// fetch any callback functions from the configuration table and
+// anchor them in the registry so they outlive the context
+pub fn load<'lua>(lua: Context<'lua>, config: &Table<'lua>) -> rlua::Result<Keys> {
+    let mut keys = Keys::new();
+    for name in NAMES {
+        if let Some(f) = config.get::<_, Option<Function>>(name)? {
+            keys.insert(name, lua.create_registry_value(f)?);
+        }
+    }
+    Ok(keys)
+}
+
+impl Hooks {
+    pub fn new(lua: Lua, keys: Keys) -> Self {
+        if keys.is_empty() {
+            return Hooks::default();
+        }
+        Hooks {
+            lua: Some(Mutex::new(lua)),
+            keys,
+        }
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.keys.keys().copied().collect()
+    }
+
+    // a job received from bitmarkd
+    pub fn on_job<F>(&self, fill: F) -> bool
+    where
+        F: for<'lua> FnOnce(&Table<'lua>) -> rlua::Result<()>,
+    {
+        self.call("on_job", fill)
+    }
+
+    // a worker found a nonce that will be submitted
+    pub fn on_nonce_found<F>(&self, fill: F) -> bool
+    where
+        F: for<'lua> FnOnce(&Table<'lua>) -> rlua::Result<()>,
+    {
+        self.call("on_nonce_found", fill)
+    }
+
+    // bitmarkd replied to a submission
+    pub fn on_reply<F>(&self, fill: F)
+    where
+        F: for<'lua> FnOnce(&Table<'lua>) -> rlua::Result<()>,
+    {
+        self.call("on_reply", fill);
+    }
+
+    // a socket lost its connection to bitmarkd
+    pub fn on_disconnect<F>(&self, fill: F)
+    where
+        F: for<'lua> FnOnce(&Table<'lua>) -> rlua::Result<()>,
+    {
+        self.call("on_disconnect", fill);
+    }
+
+    // run a callback, only an explicit false return value is a rejection
+    fn call<F>(&self, name: &str, fill: F) -> bool
+    where
+        F: for<'lua> FnOnce(&Table<'lua>) -> rlua::Result<()>,
+    {
+        let (lua, key) = match (&self.lua, self.keys.get(name)) {
+            (Some(lua), Some(key)) => (lua, key),
+            _ => return true,
+        };
+
+        let lua = lua.lock().unwrap();
+        let result = lua.context(|lua| -> rlua::Result<bool> {
+            let t = lua.create_table()?;
+            fill(&t)?;
+            let f: Function = lua.registry_value(key)?;
+            Ok(!matches!(f.call::<_, Value>(t)?, Value::Boolean(false)))
+        });
+
+        match result {
+            Ok(accept) => accept,
+            Err(e) => {
+                log::error!("hook: {} error: {}", name, e);
+                true
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.keys.keys()).finish()
+    }
+}
+
+impl serde::Serialize for Hooks {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.keys.keys())
+    }
+}
+
+impl PartialEq for Hooks {
+    fn eq(&self, other: &Self) -> bool {
+        self.names() == other.names()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hooks() {
+        let lua = Lua::new();
+        let keys = lua
+            .context(|lua| {
+                let config = lua
+                    .load(
+                        r#"
+                        return {
+                            on_job = function(t) return t.block ~= 7 end,
+                            on_reply = function(t) error("fail") end,
+                        }
+                        "#,
+                    )
+                    .eval::<Table>()?;
+                load(lua, &config)
+            })
+            .unwrap();
+        let hooks = Hooks::new(lua, keys);
+
+        assert_eq!(hooks.names(), vec!["on_job", "on_reply"]);
+        assert!(hooks.on_job(|t| t.set("block", 6)));
+        assert!(!hooks.on_job(|t| t.set("block", 7)));
+
+        // not present and failing hooks do not reject
+        assert!(hooks.on_nonce_found(|t| t.set("block", 7)));
+        hooks.on_reply(|t| t.set("reply", "{}"));
+    }
+}
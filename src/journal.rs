@@ -0,0 +1,264 @@
+// journal.rs
+
+// append only record of found nonces, one JSON object per line
+
+use serde_derive::{Deserialize, Serialize};
+use simple_error::bail;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+
+use super::block;
+use super::responder;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: String,
+    pub connection: i64,
+    pub worker: u32,
+    pub job: String,
+    pub block: u64,
+    pub version: u16,
+    pub transaction_count: u16,
+    pub previous_block: String,
+    pub merkle_root: String,
+    pub timestamp: u64,
+    pub difficulty: String,
+    pub nonce: String,
+    pub digest: String,
+    pub reply: serde_json::Value,
+    // found but not submitted, absent from older journals
+    #[serde(default)]
+    pub dry_run: bool,
+    // why a submitted nonce has no reply
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+// what became of a found nonce
+pub enum Outcome<'a> {
+    Reply(&'a str),
+    DryRun,
+    Failed(&'a str),
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum By {
+    Day,
+    Connection,
+}
+
+impl Entry {
+    // time is when the nonce was submitted, a reply that is not JSON is
+    // kept as a string
+    pub fn new(
+        connection: i64,
+        response: &responder::Response,
+        time: &str,
+        outcome: Outcome,
+    ) -> MyResult<Self> {
+        let h = block::Header::parse(&response.header)?;
+        Ok(Entry {
+            time: time.to_string(),
+            connection,
+            worker: response.worker,
+            job: response.job.clone(),
+            block: h.number,
+            version: h.version,
+            transaction_count: h.transaction_count,
+            previous_block: hex::encode(h.previous_block),
+            merkle_root: hex::encode(h.merkle_root),
+            timestamp: h.timestamp,
+            difficulty: hex::encode(h.difficulty),
+            nonce: block::nonce_hex(block::packed_nonce(&response.packed)),
+            digest: hex::encode(&response.digest),
+            reply: match outcome {
+                Outcome::Reply(reply) => serde_json::from_str(reply)
+                    .unwrap_or_else(|_| serde_json::Value::String(reply.to_string())),
+                _ => serde_json::Value::Null,
+            },
+            dry_run: matches!(outcome, Outcome::DryRun),
+            error: match outcome {
+                Outcome::Failed(e) => Some(e.to_string()),
+                _ => None,
+            },
+        })
+    }
+}
+
+pub fn append(path: &str, entry: &Entry) -> MyResult<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
+// no journal is no entries, it is created with the first found nonce
+pub fn read(path: &str) -> MyResult<Vec<Entry>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => bail!("journal: {} error: {}", path, e),
+    };
+    let mut entries = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => bail!("journal: {}:{} error: {}", path, n + 1, e),
+        }
+    }
+    Ok(entries)
+}
+
+pub fn list(entries: &[Entry]) -> String {
+    let mut s = String::new();
+    for e in entries {
+        s += &format!(
+            "{}  C{}  W{}  block: {}  nonce: {}  ",
+            e.time, e.connection, e.worker, e.block, e.nonce
+        );
+        s += &match (e.dry_run, &e.error) {
+            (true, _) => "dry run\n".to_string(),
+            (false, Some(error)) => format!("error: {}\n", error),
+            (false, None) => format!("reply: {}\n", e.reply),
+        };
+    }
+    s
+}
+
+// count of nonces, those that failed to be submitted and distinct
+// blocks for each day or connection
+pub fn summary(entries: &[Entry], by: By) -> String {
+    let mut groups: BTreeMap<String, (u64, u64, Vec<u64>)> = BTreeMap::new();
+    for e in entries {
+        let key = match by {
+            By::Day => e.time.chars().take(10).collect(),
+            By::Connection => format!("C{}", e.connection),
+        };
+        let g = groups.entry(key).or_default();
+        g.0 += 1;
+        g.1 += u64::from(e.error.is_some());
+        if !g.2.contains(&e.block) {
+            g.2.push(e.block);
+        }
+    }
+    let mut s = String::new();
+    for (key, (found, failed, blocks)) in &groups {
+        s += &format!(
+            "{}  found: {}  failed: {}  blocks: {}\n",
+            key,
+            found,
+            failed,
+            blocks.len()
+        );
+    }
+    s += &format!(
+        "total  found: {}  failed: {}\n",
+        entries.len(),
+        entries.iter().filter(|e| e.error.is_some()).count()
+    );
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal() {
+        let mut header = vec![0u8; 92];
+        header[0] = 1;
+        header[4..12].copy_from_slice(&12345u64.to_le_bytes());
+        header[84..92].copy_from_slice(&[0xff; 8]);
+        let response = responder::Response {
+            request: "block.nonce".to_string(),
+            job: "0123".to_string(),
+            packed: 0x1122u64.to_le_bytes().to_vec(),
+            header: bytes::Bytes::from(header),
+            digest: vec![0xab; 32],
+            worker: 2,
+        };
+
+        let path = std::env::temp_dir().join(format!("mt-recorder-journal-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let a = Entry::new(
+            1,
+            &response,
+            "2024-01-01T10:00:00Z",
+            Outcome::Reply(r#"{"ok":true}"#),
+        )
+        .unwrap();
+        let b = Entry::new(
+            2,
+            &response,
+            "2024-01-02T10:00:00Z",
+            Outcome::Reply("not json"),
+        )
+        .unwrap();
+        assert_eq!(a.block, 12345);
+        assert_eq!(a.version, 1);
+        assert_eq!(a.nonce, "0000000000001122");
+        assert_eq!(a.difficulty, "ffffffffffffffff");
+        assert_eq!(b.reply, serde_json::json!("not json"));
+
+        append(path, &a).unwrap();
+        append(path, &b).unwrap();
+        append(path, &a).unwrap();
+        let entries = read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(read(path).unwrap(), vec![]);
+        assert_eq!(entries[0], a);
+
+        assert_eq!(
+            summary(&entries, By::Day),
+            "2024-01-01  found: 2  failed: 0  blocks: 1\n2024-01-02  found: 1  failed: 0  blocks: 1\ntotal  found: 3  failed: 0\n"
+        );
+        assert_eq!(
+            summary(&entries, By::Connection),
+            "C1  found: 2  failed: 0  blocks: 1\nC2  found: 1  failed: 0  blocks: 1\ntotal  found: 3  failed: 0\n"
+        );
+        assert!(list(&entries).starts_with("2024-01-01T10:00:00Z  C1  W2  block: 12345"));
+
+        let c = Entry::new(1, &response, "2024-01-03T10:00:00Z", Outcome::DryRun).unwrap();
+        assert!(c.dry_run);
+        assert!(list(&[c]).ends_with("nonce: 0000000000001122  dry run\n"));
+
+        // a nonce whose submission failed is counted apart
+        let d = Entry::new(
+            1,
+            &response,
+            "2024-01-03T10:00:00Z",
+            Outcome::Failed("no reply"),
+        )
+        .unwrap();
+        assert!(!d.dry_run);
+        assert!(list(&[d]).ends_with("error: no reply\n"));
+        let d = Entry::new(
+            1,
+            &response,
+            "2024-01-03T10:00:00Z",
+            Outcome::Failed("no reply"),
+        )
+        .unwrap();
+        assert_eq!(
+            summary(&[a, d], By::Connection),
+            "C1  found: 2  failed: 1  blocks: 1\ntotal  found: 2  failed: 1\n"
+        );
+
+        // journals written before dry runs and errors were marked
+        let mut line = serde_json::to_value(&b).unwrap();
+        line.as_object_mut().unwrap().remove("dry_run");
+        line.as_object_mut().unwrap().remove("error");
+        let old: Entry = serde_json::from_value(line).unwrap();
+        assert!(!old.dry_run);
+        assert_eq!(old.error, None);
+    }
+}
@@ -0,0 +1,331 @@
+// logging.rs
+
+use log::LevelFilter;
+use log4rs::append::console::ConsoleAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::roll::Roll;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::trigger::time::{
+    TimeTrigger, TimeTriggerConfig, TimeTriggerInterval,
+};
+use log4rs::append::rolling_file::policy::compound::trigger::Trigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::Append;
+use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::json::JsonEncoder;
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::Encode;
+use log4rs::filter::threshold::ThresholdFilter;
+use simple_error::bail;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::appender;
+use super::config;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+pub fn start(logging: &config::Logging) -> MyResult<log4rs::Handle> {
+    Ok(log4rs::init_config(build(logging)?)?)
+}
+
+// log4rs configuration for the logging table
+pub fn build(logging: &config::Logging) -> MyResult<Config> {
+    let filter = level_filter(&logging.level);
+
+    // an appender without its own level passes level or a more verbose
+    // module level, the module loggers do the rest
+    let modules_filter = logging
+        .modules
+        .values()
+        .map(|l| level_filter(l))
+        .fold(filter, Ord::max);
+    let console_filter = if logging.console_level_set {
+        level_filter(&logging.console_level)
+    } else {
+        modules_filter
+    };
+    let file_filter = if logging.file_level_set {
+        level_filter(&logging.file_level)
+    } else {
+        modules_filter
+    };
+
+    let mut builder = Config::builder();
+    let mut root = Root::builder();
+    let mut root_filter = filter;
+
+    if logging.console {
+        let stdout = ConsoleAppender::builder().encoder(encoder(logging)).build();
+        builder = builder.appender(
+            Appender::builder()
+                .filter(Box::new(ThresholdFilter::new(console_filter)))
+                .build("stdout", Box::new(stdout)),
+        );
+        root = root.appender("stdout");
+        if logging.console_level_set {
+            root_filter = root_filter.max(console_filter);
+        }
+    }
+
+    for output in &logging.output {
+        let appender: Box<dyn Append> = match output.as_ref() {
+            "file" => {
+                let suffix = if logging.compress { ".gz" } else { "" };
+                let roller = FixedWindowRoller::builder().base(0).build(
+                    &format!("{}/{}.{{}}{}", logging.directory, logging.file, suffix),
+                    logging.count,
+                )?;
+                let roller = Retention {
+                    roller,
+                    directory: PathBuf::from(&logging.directory),
+                    file: logging.file.clone(),
+                    days: logging.retention_days,
+                };
+
+                let logfile = RollingFileAppender::builder()
+                    .encoder(encoder(logging))
+                    .build(
+                        format!("{}/{}", logging.directory, logging.file),
+                        Box::new(CompoundPolicy::new(trigger(logging)?, Box::new(roller))),
+                    )?;
+                Box::new(logfile)
+            }
+            "journald" => Box::new(appender::Journald::new()?),
+            "syslog" => Box::new(appender::Syslog::new(
+                &logging.syslog_address,
+                &logging.syslog_facility,
+            )?),
+            _ => bail!("invalid logging output: {}", output),
+        };
+
+        // file_level applies to the log file, the others use level
+        let threshold = if output == "file" {
+            file_filter
+        } else {
+            modules_filter
+        };
+        builder = builder.appender(
+            Appender::builder()
+                .filter(Box::new(ThresholdFilter::new(threshold)))
+                .build(output, appender),
+        );
+        root = root.appender(output);
+        if output == "file" && logging.file_level_set {
+            root_filter = root_filter.max(file_filter);
+        }
+    }
+
+    // e.g. worker = "warn" applies to mt_recorder::worker
+    for (module, level) in &logging.modules {
+        builder =
+            builder.logger(Logger::builder().build(module_target(module), level_filter(level)));
+    }
+
+    // the root must pass everything that any appender wants
+    Ok(builder.build(root.build(root_filter))?)
+}
+
+// "size" rolls when the file reaches the size limit, "hourly" and
+// "daily" roll on the hour or at midnight local time
+fn trigger(logging: &config::Logging) -> MyResult<Box<dyn Trigger>> {
+    let interval = match logging.rotate.as_ref() {
+        "size" => return Ok(Box::new(SizeTrigger::new(logging.size))),
+        "hourly" => TimeTriggerInterval::Hour(1),
+        "daily" => TimeTriggerInterval::Day(1),
+        _ => bail!("invalid logging rotate: {}", logging.rotate),
+    };
+    Ok(Box::new(TimeTrigger::new(TimeTriggerConfig {
+        interval,
+        modulate: true,
+        max_random_delay: 0,
+    })))
+}
+
+// roll the window then delete rolled files beyond the retention period
+#[derive(Debug)]
+struct Retention {
+    roller: FixedWindowRoller,
+    directory: PathBuf,
+    file: String,
+    days: u64,
+}
+
+impl Roll for Retention {
+    fn roll(&self, file: &Path) -> anyhow::Result<()> {
+        self.roller.roll(file)?;
+        if self.days > 0 {
+            prune(&self.directory, &self.file, self.days)?;
+        }
+        Ok(())
+    }
+}
+
+// rolled files are: file.N or file.N.gz
+fn prune(directory: &Path, file: &str, days: u64) -> std::io::Result<()> {
+    let cutoff = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+    let prefix = format!("{}.", file);
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let rolled = match name.to_str().and_then(|n| n.strip_prefix(&prefix)) {
+            Some(rest) => rest.starts_with(|c: char| c.is_ascii_digit()),
+            None => false,
+        };
+        if rolled && entry.metadata()?.modified()? < cutoff {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn level_filter(level: &str) -> LevelFilter {
+    match level {
+        "off" => LevelFilter::Off,
+        "error" => LevelFilter::Error,
+        "warn" => LevelFilter::Warn,
+        "info" => LevelFilter::Info,
+        "debug" => LevelFilter::Debug,
+        "trace" => LevelFilter::Trace,
+        _ => LevelFilter::Error,
+    }
+}
+
+fn module_target(module: &str) -> String {
+    let crate_name = env!("CARGO_CRATE_NAME");
+    if module == crate_name || module.contains("::") {
+        module.to_string()
+    } else {
+        format!("{}::{}", crate_name, module)
+    }
+}
+
+// "json" gives one object per line with the structured key-values
+// under "attributes", anything else is the text pattern
+fn encoder(logging: &config::Logging) -> Box<dyn Encode> {
+    match logging.format.as_ref() {
+        "json" => Box::new(JsonEncoder::new()),
+        _ => Box::new(PatternEncoder::new(&pattern_time(
+            &logging.pattern,
+            &logging.time,
+        ))),
+    }
+}
+
+// add the time zone to any date in the pattern that does not have one
+fn pattern_time(pattern: &str, time: &str) -> String {
+    let mut result = String::new();
+    let mut rest = pattern;
+    while let Some(i) = rest.find("{d") {
+        result.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+        let spec = &rest[2..end];
+        if spec.is_empty() {
+            result.push_str(&format!("{{d(%Y-%m-%dT%H:%M:%S%.6f%:z)({})}}", time));
+        } else if spec.starts_with('(') && spec.ends_with(')') && !spec.contains(")(") {
+            result.push_str(&format!("{{d{}({})}}", spec, time));
+        } else {
+            result.push_str(&rest[..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_time() {
+        assert_eq!(
+            pattern_time(config::DEFAULT_LOG_PATTERN, "utc"),
+            "{d(%Y-%m-%d %H:%M:%S)(utc)} [{l}] {M}: {m}{n}"
+        );
+        assert_eq!(
+            pattern_time("{d(%H:%M)(utc)} {m}", "local"),
+            "{d(%H:%M)(utc)} {m}"
+        );
+        assert_eq!(
+            pattern_time("{d} {m}{n}", "local"),
+            "{d(%Y-%m-%dT%H:%M:%S%.6f%:z)(local)} {m}{n}"
+        );
+        assert_eq!(module_target("worker"), "mt_recorder::worker");
+        assert_eq!(module_target("mt_recorder"), "mt_recorder");
+    }
+
+    #[test]
+    fn test_module_levels() {
+        let cfg = config::read(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/mt-recorder.conf.sample"),
+            false,
+        );
+        let mut logging = cfg.unwrap().logging;
+        logging.console = true;
+        logging.output.clear();
+        logging.level = "warn".to_string();
+        logging.console_level = "warn".to_string();
+        logging
+            .modules
+            .insert("responder".to_string(), "debug".to_string());
+
+        let record = log::Record::builder()
+            .level(log::Level::Debug)
+            .target("mt_recorder::responder")
+            .build();
+        let passes = |logging: &config::Logging| {
+            let c = build(logging).unwrap();
+            c.appenders()[0]
+                .filters()
+                .iter()
+                .all(|f| !matches!(f.filter(&record), log4rs::filter::Response::Reject))
+        };
+
+        // a module can be more verbose than level
+        assert!(passes(&logging));
+
+        // unless the console level was set
+        logging.console_level_set = true;
+        assert!(!passes(&logging));
+    }
+
+    #[test]
+    fn test_prune() {
+        let directory =
+            std::env::temp_dir().join(format!("mt-recorder-prune-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let old = SystemTime::now() - Duration::from_secs(3 * 24 * 60 * 60);
+        for name in [
+            "test.log",
+            "test.log.0",
+            "test.log.1.gz",
+            "test.log.x",
+            "other.log.0",
+        ] {
+            let f = std::fs::File::create(directory.join(name)).unwrap();
+            f.set_modified(old).unwrap();
+        }
+        std::fs::File::create(directory.join("test.log.2")).unwrap();
+
+        prune(&directory, "test.log", 2).unwrap();
+
+        let mut names: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["other.log.0", "test.log", "test.log.2", "test.log.x"]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
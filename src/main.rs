@@ -2,30 +2,51 @@
 
 use base64;
 use base64_serde::base64_serde_type;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use hex;
 use log;
-use log::LevelFilter;
-use log4rs::append::console::ConsoleAppender;
-use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
-use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
-use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
-use log4rs::append::rolling_file::RollingFileAppender;
-use log4rs::config::{Appender, Config, Root};
-use log4rs::encode::pattern::PatternEncoder;
 use simple_error::bail;
 use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use zmq;
 
+mod appender;
+mod backend;
+mod benchmark;
 mod block;
 mod config;
+mod control;
+mod daemon;
+mod dump;
+mod hooks;
+mod journal;
+mod logging;
+mod notify;
+mod record;
+mod reference;
+mod resources;
 mod responder;
+mod schedule;
+mod selftest;
+mod simulate;
+mod statistics;
+mod throttle;
+mod verify;
 mod worker;
 
 base64_serde_type!(Base64Standard, base64::engine::general_purpose::STANDARD);
 
 type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
 
+// longest time the poller and sender block before showing progress
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+// longest wait for bitmarkd to answer a submitted nonce, after which
+// the request socket is replaced since REQ cannot send again
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -38,95 +59,482 @@ struct Args {
     debug: bool,
 
     /// configuration file
-    #[arg(short, long)]
-    config: String,
+    #[arg(short, long, env = "MT_RECORDER_CONFIG")]
+    config: Option<String>,
+
+    /// override the logging level
+    #[arg(long, env = "MT_RECORDER_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// override logging to the console
+    #[arg(long, env = "MT_RECORDER_LOG_CONSOLE")]
+    log_console: Option<bool>,
+
+    /// override running in the background
+    #[arg(long, env = "MT_RECORDER_DAEMON")]
+    daemon: Option<bool>,
+
+    /// hash but log and journal found nonces instead of submitting them
+    #[arg(long, env = "MT_RECORDER_DRY_RUN", default_value_t = false)]
+    dry_run: bool,
+
+    /// override the workers for a connection, e.g. C1=4; merged with
+    /// MT_RECORDER_WORKERS, replacing only the connections given
+    #[arg(long, value_delimiter = ',', value_parser = config::parse_workers)]
+    workers: Vec<(i64, u32)>,
+
+    /// enable a connection, e.g. C1; over MT_RECORDER_ENABLE and
+    /// MT_RECORDER_DISABLE for the connections given
+    #[arg(long, value_delimiter = ',', value_parser = config::parse_connection)]
+    enable: Vec<i64>,
+
+    /// disable a connection, e.g. C2; over MT_RECORDER_ENABLE and
+    /// MT_RECORDER_DISABLE for the connections given
+    #[arg(long, value_delimiter = ',', value_parser = config::parse_connection)]
+    disable: Vec<i64>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// print the effective configuration after defaults and overrides
+    DumpConfig {
+        /// output format
+        #[arg(short, long, value_enum, default_value_t = dump::Format::Json)]
+        format: dump::Format,
+    },
+
+    /// print a commented sample configuration
+    PrintSampleConfig,
+
+    /// print the digest of a job or header and nonce and check its difficulty
+    Verify {
+        /// job JSON or hex header, as text, a file or - for stdin
+        input: String,
+
+        /// nonce in hex as logged, replacing any in the input
+        nonce: Option<String>,
+
+        /// hash with the digest parameters of this connection, e.g. C1,
+        /// instead of bitmarkd's
+        #[arg(short, long, value_parser = config::parse_connection)]
+        connection: Option<i64>,
+    },
+
+    /// decode a header from hex or a raw file
+    Inspect {
+        /// hex header as text, a file or - for stdin
+        input: String,
+    },
+
+    /// feed a recorded job stream to the workers without connecting
+    Replay {
+        /// file written by the record option of a connection
+        file: String,
+
+        /// connection whose workers and hooks are used, e.g. C1
+        #[arg(short, long, default_value = "C1", value_parser = config::parse_connection)]
+        connection: i64,
+
+        /// pace relative to the recording, 0 for no delay
+        #[arg(short, long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// seconds to keep hashing the last job
+        #[arg(short, long, default_value_t = 10)]
+        tail: u64,
+    },
+
+    /// hash locally generated jobs and check the nonces found
+    Simulate {
+        /// connection whose workers and hooks are used, e.g. C1
+        #[arg(short, long, default_value = "C1", value_parser = config::parse_connection)]
+        connection: i64,
+
+        /// difficulty in hex as in a job
+        #[arg(long, default_value = "ffffffffffffff00", value_parser = simulate::parse_difficulty)]
+        difficulty: [u8; 8],
+
+        /// seconds between jobs
+        #[arg(short, long, default_value_t = 120)]
+        interval: u64,
+
+        /// seconds to run, 0 is until interrupted
+        #[arg(short, long, default_value_t = 0)]
+        duration: u64,
+
+        /// first block number
+        #[arg(long, default_value_t = 1)]
+        block: u64,
+
+        /// random seed for repeatable jobs, default from the time
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+
+    /// compare hash rates of thread counts and interleaved digests
+    Benchmark {
+        /// connection whose digest parameters are used, e.g. C1
+        #[arg(short, long, default_value = "C1", value_parser = config::parse_connection)]
+        connection: i64,
+
+        /// thread counts to try, default 1 and the available CPUs
+        #[arg(short, long, value_delimiter = ',')]
+        threads: Vec<u32>,
+
+        /// digests per thread to try
+        #[arg(short, long, value_delimiter = ',', default_value = "1,2,4")]
+        interleave: Vec<u32>,
+
+        /// seconds to run each combination
+        #[arg(short, long, default_value_t = 10)]
+        seconds: u64,
+    },
+
+    /// list or summarise the found nonce journal
+    Journal {
+        /// group the summary by
+        #[arg(short, long, value_enum, default_value_t = journal::By::Day)]
+        by: journal::By,
+
+        /// list every entry instead
+        #[arg(short, long, default_value_t = false)]
+        list: bool,
+
+        /// only the nonces found in dry runs, which are otherwise left out
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// send a command to the running recorder, e.g. status, pause C1
+    Ctl {
+        #[arg(required = true, trailing_var_arg = true)]
+        command: Vec<String>,
+    },
+}
 
 fn main() -> MyResult<()> {
     let args = Args::parse();
 
+    if let Some(Command::Verify {
+        input,
+        nonce,
+        connection,
+    }) = &args.command
+    {
+        let params = match (connection, &args.config) {
+            (None, _) => block::LIVE,
+            (Some(n), Some(filename)) => {
+                let cfg = config::read(filename, args.debug)?;
+                match cfg.connections.iter().find(|c| c.number == *n) {
+                    Some(c) => block::Params::new(&c.argon2)?,
+                    None => bail!("verify: no connection: C{}", n),
+                }
+            }
+            (Some(_), None) => bail!("verify: a configuration file is required for a connection"),
+        };
+        print!("{}", verify::run(input, nonce.as_deref(), &params)?);
+        return Ok(());
+    }
+
+    if let Some(Command::Inspect { input }) = &args.command {
+        print!("{}", verify::inspect(input)?);
+        return Ok(());
+    }
+
+    if let Some(Command::PrintSampleConfig) = args.command {
+        print!("{}", dump::sample());
+        return Ok(());
+    }
+
+    let filename = match &args.config {
+        Some(filename) => filename,
+        None => bail!("a configuration file is required"),
+    };
+
     let debug = args.debug;
-    let cfg = config::read(&args.config, debug)?;
+    let mut cfg = config::read(filename, debug)?;
 
     if debug {
         println!("Value for args: {:?}", args);
         println!("Value for cfg: {:?}", cfg);
     }
 
-    if !Path::new(&cfg.logging.directory).exists() {
+    // command line and environment take precedence over the file
+    let (enable, disable) = config::merge_enable(
+        std::env::var("MT_RECORDER_ENABLE").ok().as_deref(),
+        std::env::var("MT_RECORDER_DISABLE").ok().as_deref(),
+        &args.enable,
+        &args.disable,
+    )?;
+    let overrides = config::Overrides {
+        log_level: args.log_level.clone(),
+        log_console: args.log_console,
+        daemon: args.daemon,
+        dry_run: args.dry_run,
+        workers: config::merge_workers(
+            std::env::var("MT_RECORDER_WORKERS").ok().as_deref(),
+            &args.workers,
+        )?,
+        enable,
+        disable,
+    };
+    config::apply_overrides(&mut cfg, &overrides)?;
+
+    if debug {
+        println!("Value for overrides: {:?}", overrides);
+        println!("Effective cfg: {:?}", cfg);
+    }
+
+    for connection in &cfg.connections {
+        if let Err(e) = schedule::Schedule::parse(&connection.schedule) {
+            bail!("C{}: {}", connection.number, e);
+        }
+        if let Err(e) = block::Params::new(&connection.argon2) {
+            bail!("C{}: {}", connection.number, e);
+        }
+    }
+    let backend = backend::select(&cfg.argon2_backend)?;
+    // the library hashes one digest at a time, interleaving would only
+    // multiply the memory and the time between checks for a new job
+    for connection in &cfg.connections {
+        if connection.interleave > 1
+            && !backend::handles(backend, &block::Params::new(&connection.argon2)?)
+        {
+            bail!(
+                "C{}: interleave: {} needs argon2d version 0x13 and a backend other than library",
+                connection.number,
+                connection.interleave
+            );
+        }
+    }
+
+    let throttle = Arc::new(throttle::Throttle::new(&cfg.throttle)?);
+
+    if let Some(Command::DumpConfig { format }) = args.command {
+        // the effective worker counts, without refusing an overcommit
+        resources::resolve_workers(&mut cfg, &resources::limits());
+        print!("{}", dump::configuration(&cfg, format)?);
+        return Ok(());
+    }
+
+    if let Some(Command::Journal { by, list, dry_run }) = args.command {
+        let mut entries = journal::read(&cfg.journal)?;
+        entries.retain(|e| e.dry_run == dry_run);
+        if list {
+            print!("{}", journal::list(&entries));
+        } else {
+            print!("{}", journal::summary(&entries, by));
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Ctl { command }) = &args.command {
+        print!("{}", control::send(&cfg.control_socket, command)?);
+        return Ok(());
+    }
+
+    if let Some(Command::Simulate {
+        connection,
+        difficulty,
+        interval,
+        duration,
+        block,
+        seed,
+    }) = &args.command
+    {
+        let i = match cfg.connections.iter().position(|c| c.number == *connection) {
+            Some(i) => i,
+            None => bail!("simulate: no connection: C{}", connection),
+        };
+        let memory_warning = resources::size_connection(
+            &mut cfg.connections[i],
+            &cfg.memory_check,
+            &resources::limits(),
+        )?;
+        let c = &cfg.connections[i];
+        let options = simulate::Options {
+            difficulty: *difficulty,
+            interval: *interval,
+            duration: *duration,
+            block: *block,
+            seed: seed
+                .unwrap_or_else(|| chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64),
+        };
+        let _handle = logging::start(&cfg.logging)?;
+        if let Some(warning) = memory_warning {
+            log::warn!("{}", warning);
+        }
+        print!("{}", simulate::run(&cfg, c, &options)?);
+        return Ok(());
+    }
+
+    if let Some(Command::Benchmark {
+        connection,
+        threads,
+        interleave,
+        seconds,
+    }) = &args.command
+    {
+        let c = match cfg.connections.iter().find(|c| c.number == *connection) {
+            Some(c) => c,
+            None => bail!("benchmark: no connection: C{}", connection),
+        };
+        if let Some(n) = interleave
+            .iter()
+            .find(|n| !(1..=config::MAXIMUM_INTERLEAVE).contains(*n))
+        {
+            bail!("benchmark: invalid interleave: {}", n);
+        }
+        let limits = resources::limits();
+        let mut threads = threads.clone();
+        if threads.is_empty() {
+            threads.push(1);
+            if limits.cpus > 1 {
+                threads.push(limits.cpus);
+            }
+        }
+        let options = benchmark::Options {
+            threads,
+            interleave: interleave.clone(),
+            duration: Duration::from_secs(*seconds),
+        };
+        let _handle = logging::start(&cfg.logging)?;
+        let params = block::Params::new(&c.argon2)?;
+        print!("{}", benchmark::run(&params, &options, limits.memory)?);
+        return Ok(());
+    }
+
+    if let Some(Command::Replay {
+        file,
+        connection,
+        speed,
+        tail,
+    }) = &args.command
+    {
+        let i = match cfg.connections.iter().position(|c| c.number == *connection) {
+            Some(i) => i,
+            None => bail!("replay: no connection: C{}", connection),
+        };
+        let memory_warning = resources::size_connection(
+            &mut cfg.connections[i],
+            &cfg.memory_check,
+            &resources::limits(),
+        )?;
+        let c = &cfg.connections[i];
+        // as given or in data_directory, where the recording is made
+        let file = if Path::new(file).exists() {
+            file.clone()
+        } else {
+            format!("{}/{}", cfg.data_directory, file)
+        };
+        let _handle = logging::start(&cfg.logging)?;
+        if let Some(warning) = memory_warning {
+            log::warn!("{}", warning);
+        }
+        print!("{}", record::replay(&cfg, c, &file, *speed, *tail)?);
+        return Ok(());
+    }
+
+    if cfg.logging.output.iter().any(|o| o == "file") && !Path::new(&cfg.logging.directory).exists()
+    {
         bail!(
             "logging directory: {} does not exist",
             cfg.logging.directory
         );
     }
 
-    let pattern = "{d(%Y-%m-%d %H:%M:%S)(utc)} [{l}] {M}: {m}{n}";
-    let stdout = ConsoleAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(pattern)))
-        .build();
-
-    let roller = FixedWindowRoller::builder()
-        .base(0)
-        .build(
-            &format!("{}/{}.{{}}", cfg.logging.directory, cfg.logging.file),
-            cfg.logging.count,
-        )
-        .unwrap();
-
-    let logfile = RollingFileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(pattern)))
-        .build(
-            &format!("{}/{}", cfg.logging.directory, cfg.logging.file),
-            Box::new(CompoundPolicy::new(
-                Box::new(SizeTrigger::new(cfg.logging.size)),
-                Box::new(roller),
-            )),
-        )
-        .unwrap();
-
-    let filter = match cfg.logging.level.as_ref() {
-        "off" => LevelFilter::Off,
-        "error" => LevelFilter::Error,
-        "warn" => LevelFilter::Warn,
-        "info" => LevelFilter::Info,
-        "debug" => LevelFilter::Debug,
-        "trace" => LevelFilter::Trace,
-        _ => LevelFilter::Error,
-    };
+    // size "auto" workers and check they all fit in memory
+    let limits = resources::limits();
+    resources::resolve_workers(&mut cfg, &limits);
+    let memory_warning = resources::check_memory(&cfg, &limits)?;
 
-    let config = if cfg.logging.console {
-        Config::builder().appender(Appender::builder().build("stdout", Box::new(stdout)))
-    } else {
-        Config::builder()
+    // refuse to hash with a backend that gives wrong digests
+    selftest::check()?;
+
+    // only one recorder per data directory
+    let mut pidfile = daemon::Pidfile::lock(&cfg.pidfile)?;
+    if cfg.daemon {
+        daemon::detach()?;
     }
-    .appender(Appender::builder().build("logfile", Box::new(logfile)))
-    .build(
-        if cfg.logging.console {
-            Root::builder().appender("stdout")
-        } else {
-            Root::builder()
-        }
-        .appender("logfile")
-        .build(filter),
-    )
-    .unwrap();
+    pidfile.write_pid()?;
 
     // start logging
-    let _handle = log4rs::init_config(config).unwrap();
+    let handle = logging::start(&cfg.logging)?;
     log::warn!("=== start ===");
+    log::info!(
+        "available cpus: {}  memory: {} MiB",
+        limits.cpus,
+        limits.memory >> 20
+    );
+    if let Some(warning) = memory_warning {
+        log::warn!("{}", warning);
+    }
+    log::info!(
+        "argon2 backend: {}  cpu features: {}",
+        backend.name(),
+        backend::features().join(" ")
+    );
+    log::info!("self test: passed");
+
+    // keys have been read and log files opened
+    if let Err(e) = daemon::drop_privileges(&cfg.user, &cfg.group) {
+        log::error!("{}", e);
+        return Err(e);
+    }
 
     // open connections
     let mut handles = Vec::new();
+    let mut stats = Vec::new();
+    let mut running = Vec::new();
     for connection in cfg.connections {
         if connection.enable && connection.public_key != "" {
             log::debug!("connection: {}", connection.number);
-            handles.push(create_connection(connection)?);
+            if connection.auto_workers {
+                log::info!(
+                    "C{}: auto workers: {}",
+                    connection.number,
+                    connection.workers
+                );
+            }
+            let s = Arc::new(statistics::Connection::new(connection.number));
+            let (handle, pool) = create_connection(
+                connection,
+                cfg.hooks.clone(),
+                s.clone(),
+                throttle.clone(),
+                cfg.journal.clone(),
+                cfg.verify_digest,
+                cfg.self_test_interval,
+            )?;
+            worker::supervise(s.number, pool.clone(), cfg.stall_seconds);
+            handles.push(handle);
+            running.push(control::Connection {
+                stats: s.clone(),
+                pool,
+            });
+            stats.push(s);
         } else {
             log::debug!("connection: {} is disabled", connection.number);
         }
     }
 
+    throttle::spawn(throttle.clone());
+    control::spawn(
+        &cfg.control_socket,
+        running,
+        cfg.logging,
+        handle,
+        throttle,
+        cfg.memory_check,
+        limits,
+    )?;
+
+    // tell systemd when ready and keep its watchdog fed
+    notify::spawn(stats);
+
     for handle in handles {
         handle.join().unwrap();
     }
@@ -134,12 +542,21 @@ fn main() -> MyResult<()> {
     Ok(())
 }
 
-fn create_connection(connection: config::Connection) -> MyResult<std::thread::JoinHandle<()>> {
+fn create_connection(
+    connection: config::Connection,
+    hooks: Arc<hooks::Hooks>,
+    stats: Arc<statistics::Connection>,
+    throttle: Arc<throttle::Throttle>,
+    journal: String,
+    verify_digest: bool,
+    self_test_interval: u64,
+) -> MyResult<(std::thread::JoinHandle<()>, Arc<Mutex<worker::Pool>>)> {
     let set = connection.number;
+    let record = connection.record.clone();
+    let dry_run = connection.dry_run;
 
     let context = zmq::Context::new();
     let subscriber = context.socket(zmq::SUB)?;
-    let requester = context.socket(zmq::REQ)?;
 
     let client_pair = zmq::CurveKeyPair::new()?;
 
@@ -164,60 +581,196 @@ fn create_connection(connection: config::Connection) -> MyResult<std::thread::Jo
     let s = b""; // empty string ⇒ subscribe to everything
     subscriber.set_subscribe(s)?;
 
-    // setup encrypted request connection
-    requester.set_ipv6(!connection.use_ipv4)?;
-    requester.set_curve_server(false)?;
-    requester
-        .set_curve_serverkey(&server_public_key)
-        .expect("server public");
-    requester.set_curve_publickey(&client_pair.public_key)?;
-    requester.set_curve_secretkey(&client_pair.secret_key)?;
+    // watch for connection and disconnection
+    monitor(
+        set,
+        &context,
+        &subscriber,
+        "subscribe",
+        0,
+        hooks.clone(),
+        stats.clone(),
+    )?;
 
     // connect
     log::debug!("C{}: connecting…", set);
     subscriber
         .connect(&subscriber_address)
         .expect("could not connect to publisher");
-    requester
-        .connect(&requester_address)
-        .expect("could not connect to requester");
+    let mut requester = Requester {
+        set,
+        context: context.clone(),
+        address: requester_address,
+        use_ipv4: connection.use_ipv4,
+        server_public_key,
+        public_key: client_pair.public_key.to_vec(),
+        secret_key: client_pair.secret_key.to_vec(),
+        hooks: hooks.clone(),
+        stats: stats.clone(),
+        generation: 0,
+    };
+    let mut request_socket = Some(requester.socket()?);
 
     let (response_tx, response_rx) = std::sync::mpsc::channel::<responder::Response>();
 
     let workers = connection.workers;
-    let handles = worker::create_workers(set, workers, response_tx);
+    let mut settings = worker::Settings {
+        schedule: schedule::Schedule::parse(&connection.schedule)?,
+        throttle,
+        verify_digest,
+        self_test_interval: match self_test_interval {
+            0 => None,
+            n => Some(Duration::from_secs(n)),
+        },
+        interleave: connection.interleave,
+        ..Default::default()
+    };
+    settings.set_params(set, &connection.argon2)?;
+    if connection.interleave > 1 {
+        log::info!(
+            "C{}: interleave: {} digests per worker",
+            set,
+            connection.interleave
+        );
+    }
+    let pool = worker::create_workers(
+        set,
+        workers,
+        response_tx,
+        hooks.clone(),
+        stats.clone(),
+        settings,
+    );
+    let pool = Arc::new(Mutex::new(pool));
 
     // zmq sender
+    let sender_hooks = hooks.clone();
+    let sender_stats = stats.clone();
     let _sender = std::thread::spawn(move || {
         loop {
             log::debug!("C{}: waiting..", set);
-            let request = response_rx.recv().unwrap();
+            sender_stats.sender.beat();
+            if sender_stats
+                .reconnect_request
+                .swap(false, Ordering::Relaxed)
+            {
+                log::warn!("C{}: reconnecting to: {}", set, requester.address);
+                request_socket = requester.replace(request_socket.take());
+            } else if request_socket.is_none() {
+                request_socket = requester.replace(None);
+            }
+            let request = match response_rx.recv_timeout(HEARTBEAT) {
+                Ok(request) => request,
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            sender_stats.found.fetch_add(1, Ordering::Relaxed);
             log::debug!(
-                "C{}: send: {}  packed: {:02x?}",
+                "C{}: send: {}  nonce: {}",
                 set,
                 request.job,
-                request.packed
+                block::nonce_hex(block::packed_nonce(&request.packed))
             );
 
             let s = serde_json::to_string(&request).unwrap();
-            log::info!("C{}: request JSON: {}", set, s);
-            requester.send(zmq::Message::from(&s), 0).unwrap();
+            log::info!(
+                connection = set,
+                job = request.job.as_str(),
+                nonce:% = block::nonce_hex(block::packed_nonce(&request.packed));
+                "C{}: request JSON: {}",
+                set,
+                s
+            );
+            let time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+            if dry_run {
+                log::warn!(
+                    connection = set,
+                    job = request.job.as_str();
+                    "C{}: dry run, not sent: {}",
+                    set,
+                    s
+                );
+                let entry = journal::Entry::new(set, &request, &time, journal::Outcome::DryRun);
+                if let Err(e) = entry.and_then(|entry| journal::append(&journal, &entry)) {
+                    log::error!("C{}: journal: {} error: {}", set, journal, e);
+                }
+                continue;
+            }
+            // a nonce without a reply is journalled with the error and
+            // dropped, the job has likely moved on by the time the
+            // connection is back
+            let sent = request_socket.as_ref().map(|socket| {
+                socket
+                    .send(zmq::Message::from(&s), 0)
+                    .and_then(|_| socket.recv_msg(0))
+            });
+            let data = match sent {
+                Some(Ok(data)) => data,
+                failed => {
+                    let e = match failed {
+                        Some(Err(zmq::Error::EAGAIN)) => format!("no reply in {:?}", REPLY_TIMEOUT),
+                        Some(Err(e)) => e.to_string(),
+                        _ => "no request socket".to_string(),
+                    };
+                    log::error!(
+                        connection = set,
+                        job = request.job.as_str();
+                        "C{}: request: {}  nonce dropped, reconnecting to: {}",
+                        set,
+                        e,
+                        requester.address
+                    );
+                    let entry =
+                        journal::Entry::new(set, &request, &time, journal::Outcome::Failed(&e));
+                    if let Err(e) = entry.and_then(|entry| journal::append(&journal, &entry)) {
+                        log::error!("C{}: journal: {} error: {}", set, journal, e);
+                    }
+                    request_socket = requester.replace(request_socket.take());
+                    continue;
+                }
+            };
+            let reply = String::from_utf8_lossy(&data);
+            let reply = reply.as_ref();
+            sender_stats.replies.fetch_add(1, Ordering::Relaxed);
+            log::info!(
+                connection = set,
+                job = request.job.as_str(),
+                reply = reply;
+                "C{}: reply JSON: {}",
+                set,
+                reply
+            );
+
+            let entry = journal::Entry::new(set, &request, &time, journal::Outcome::Reply(reply));
+            if let Err(e) = entry.and_then(|entry| journal::append(&journal, &entry)) {
+                log::error!("C{}: journal: {} error: {}", set, journal, e);
+            }
 
-            let data = requester.recv_msg(0).unwrap();
-            let reply = std::str::from_utf8(&data).unwrap();
-            log::info!("C{}: reply JSON: {}", set, reply);
+            sender_hooks.on_reply(|t| {
+                t.set("connection", set)?;
+                t.set("job", request.job.as_str())?;
+                t.set(
+                    "nonce",
+                    block::nonce_hex(block::packed_nonce(&request.packed)),
+                )?;
+                t.set("request", s.as_str())?;
+                t.set("reply", reply)
+            });
         }
         //drop(requester);
     });
 
     // poller
-    let mut txs = handles.channel_txs;
-
+    let poller_pool = pool.clone();
     let poller = std::thread::spawn(move || {
         let items = &mut [subscriber.as_poll_item(zmq::POLLIN)];
         loop {
             log::debug!("C{}: polling…", set);
-            let n = match zmq::poll(items, -1) {
+            stats.poller.beat();
+            if stats.reconnect_subscribe.swap(false, Ordering::Relaxed) {
+                reconnect(set, &subscriber, &subscriber_address);
+            }
+            let n = match zmq::poll(items, HEARTBEAT.as_millis() as i64) {
                 Ok(n) => n,
                 Err(_) => 0,
             };
@@ -228,8 +781,17 @@ fn create_connection(connection: config::Connection) -> MyResult<std::thread::Jo
                 log::trace!("C{}: JSON: {}", set, s);
                 log::debug!("C{}: decoded: {}", set, std::str::from_utf8(&data).unwrap());
 
-                match responder::send_job(set, s, &mut txs) {
-                    Ok(_) => log::debug!("send_job success"),
+                if !record.is_empty() {
+                    if let Err(e) = record::append(&record, s) {
+                        log::error!("C{}: record: {} error: {}", set, record, e);
+                    }
+                }
+
+                match responder::send_job(set, s, &mut poller_pool.lock().unwrap(), &hooks) {
+                    Ok(_) => {
+                        stats.jobs.fetch_add(1, Ordering::Relaxed);
+                        log::debug!("send_job success")
+                    }
                     Err(e) => log::error!("send_job error: {}", e),
                 };
             }
@@ -242,5 +804,129 @@ fn create_connection(connection: config::Connection) -> MyResult<std::thread::Jo
         //}
     });
 
-    Ok(poller)
+    Ok((poller, pool))
+}
+
+// the settings to make a request socket again, bitmarkd is asked
+// through a fresh socket when a reply is lost
+struct Requester {
+    set: i64,
+    context: zmq::Context,
+    address: String,
+    use_ipv4: bool,
+    server_public_key: Vec<u8>,
+    public_key: Vec<u8>,
+    secret_key: Vec<u8>,
+    hooks: Arc<hooks::Hooks>,
+    stats: Arc<statistics::Connection>,
+    generation: u64,
+}
+
+impl Requester {
+    // an encrypted, monitored and connected request socket
+    fn socket(&mut self) -> MyResult<zmq::Socket> {
+        let socket = self.context.socket(zmq::REQ)?;
+        socket.set_ipv6(!self.use_ipv4)?;
+        socket.set_curve_server(false)?;
+        socket.set_curve_serverkey(&self.server_public_key)?;
+        socket.set_curve_publickey(&self.public_key)?;
+        socket.set_curve_secretkey(&self.secret_key)?;
+        socket.set_rcvtimeo(REPLY_TIMEOUT.as_millis() as i32)?;
+        socket.set_sndtimeo(REPLY_TIMEOUT.as_millis() as i32)?;
+        socket.set_linger(0)?;
+        self.generation += 1;
+        monitor(
+            self.set,
+            &self.context,
+            &socket,
+            "request",
+            self.generation,
+            self.hooks.clone(),
+            self.stats.clone(),
+        )?;
+        socket.connect(&self.address)?;
+        Ok(socket)
+    }
+
+    // a new socket in place of one that is stuck waiting for a reply,
+    // which can never send again; none if that fails, to be tried again
+    fn replace(&mut self, old: Option<zmq::Socket>) -> Option<zmq::Socket> {
+        drop(old);
+        // before the new monitor can report the handshake
+        self.stats.request_connected.store(false, Ordering::Relaxed);
+        match self.socket() {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                log::error!("C{}: request socket: {}", self.set, e);
+                None
+            }
+        }
+    }
+}
+
+// drop and re-establish a connection, the monitor reports the result
+fn reconnect(set: i64, socket: &zmq::Socket, address: &str) {
+    log::warn!("C{}: reconnecting to: {}", set, address);
+    if let Err(e) = socket.disconnect(address) {
+        log::error!("C{}: disconnect: {} error: {}", set, address, e);
+    }
+    if let Err(e) = socket.connect(address) {
+        log::error!("C{}: connect: {} error: {}", set, address, e);
+    }
+}
+
+// track the connection state and report socket disconnections to
+// the on_disconnect hook
+fn monitor(
+    set: i64,
+    context: &zmq::Context,
+    socket: &zmq::Socket,
+    name: &'static str,
+    generation: u64,
+    hooks: Arc<hooks::Hooks>,
+    stats: Arc<statistics::Connection>,
+) -> MyResult<()> {
+    let endpoint = format!("inproc://monitor-C{}-{}-{}", set, name, generation);
+    let mask = zmq::SocketEvent::HANDSHAKE_SUCCEEDED as i32
+        | zmq::SocketEvent::DISCONNECTED as i32
+        | zmq::SocketEvent::MONITOR_STOPPED as i32;
+    socket.monitor(&endpoint, mask)?;
+
+    let events = context.socket(zmq::PAIR)?;
+    events.connect(&endpoint)?;
+
+    std::thread::spawn(move || {
+        // frame 1: u16 event + u32 value, frame 2: endpoint address
+        while let Ok(frames) = events.recv_multipart(0) {
+            if frames.len() != 2 || frames[0].len() != 6 {
+                continue;
+            }
+            let event = u16::from_ne_bytes([frames[0][0], frames[0][1]]);
+            // the socket was closed
+            if event == zmq::SocketEvent::MONITOR_STOPPED.to_raw() {
+                break;
+            }
+            let address = String::from_utf8_lossy(&frames[1]).to_string();
+
+            let connected = match name {
+                "subscribe" => &stats.subscribe_connected,
+                _ => &stats.request_connected,
+            };
+
+            if event == zmq::SocketEvent::HANDSHAKE_SUCCEEDED.to_raw() {
+                log::info!("C{}: {}: connected to: {}", set, name, address);
+                connected.store(true, Ordering::Relaxed);
+            } else if event == zmq::SocketEvent::DISCONNECTED.to_raw() {
+                connected.store(false, Ordering::Relaxed);
+                log::warn!("C{}: {}: disconnected from: {}", set, name, address);
+                hooks.on_disconnect(|t| {
+                    t.set("connection", set)?;
+                    t.set("socket", name)?;
+                    t.set("address", address.as_str())
+                });
+            }
+        }
+    });
+
+    Ok(())
 }
@@ -0,0 +1,129 @@
+// notify.rs
+
+// systemd service notification using the NOTIFY_SOCKET protocol
+
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::statistics;
+
+// how often to update the STATUS= line
+const STATUS_SECONDS: u64 = 10;
+
+// send a notification, false if not running under systemd
+pub fn send(state: &str) -> bool {
+    let address = match socket_address() {
+        Some(address) => address,
+        None => return false,
+    };
+    let result = UnixDatagram::unbound().and_then(|s| s.send_to_addr(state.as_bytes(), &address));
+    match result {
+        Ok(_) => true,
+        Err(e) => {
+            log::error!("notify: {} error: {}", state, e);
+            false
+        }
+    }
+}
+
+// "@name" is an abstract socket, otherwise a path
+fn socket_address() -> Option<SocketAddr> {
+    let path = std::env::var("NOTIFY_SOCKET").ok()?;
+    let address = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    };
+    match address {
+        Ok(address) => Some(address),
+        Err(e) => {
+            log::error!("notify: NOTIFY_SOCKET={} error: {}", path, e);
+            None
+        }
+    }
+}
+
+// watchdog period requested by the service manager for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+// READY=1 once every connection is up and its workers running, then
+// periodic STATUS= hash rates and WATCHDOG=1 while all threads are alive
+pub fn spawn(connections: Vec<Arc<statistics::Connection>>) {
+    if socket_address().is_none() {
+        return;
+    }
+    let watchdog = watchdog_interval();
+
+    std::thread::spawn(move || {
+        let mut ready = false;
+        let mut rates: Vec<statistics::Rate> = connections
+            .iter()
+            .map(|c| statistics::Rate::new(c.hashes.load(Ordering::Relaxed)))
+            .collect();
+        let mut last_status = Instant::now();
+        let mut last_ping = Instant::now();
+
+        send("STATUS=connecting…");
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+
+            if !ready && connections.iter().all(|c| is_ready(c)) {
+                log::info!("notify: ready");
+                send("READY=1");
+                ready = true;
+            }
+
+            if ready && last_status.elapsed() >= Duration::from_secs(STATUS_SECONDS) {
+                let mut total = 0.0;
+                let mut status = Vec::new();
+                for (c, rate) in connections.iter().zip(rates.iter_mut()) {
+                    let r = rate.sample(c.hashes.load(Ordering::Relaxed));
+                    total += r;
+                    let held = if c.paused.load(Ordering::Relaxed) {
+                        " (paused)"
+                    } else if c.off_schedule.load(Ordering::Relaxed) {
+                        " (off schedule)"
+                    } else {
+                        ""
+                    };
+                    status.push(format!("C{}: {:.2} H/s{}", c.number, r, held));
+                }
+                send(&format!(
+                    "STATUS={}  total: {:.2} H/s",
+                    status.join("  "),
+                    total
+                ));
+                last_status = Instant::now();
+            }
+
+            if let Some(interval) = watchdog {
+                if last_ping.elapsed() >= interval / 2 {
+                    if connections.iter().all(|c| c.healthy(interval)) {
+                        send("WATCHDOG=1");
+                    } else {
+                        log::warn!("notify: threads not alive, withholding watchdog");
+                    }
+                    last_ping = Instant::now();
+                }
+            }
+        }
+    });
+}
+
+fn is_ready(c: &statistics::Connection) -> bool {
+    let alive = c.workers_alive();
+    c.connected() && alive > 0 && alive == c.workers.lock().unwrap().len()
+}
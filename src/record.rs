@@ -0,0 +1,191 @@
+// record.rs
+
+// the raw job stream of a connection, one JSON object per line with
+// its receive time, and its replay through the workers without any
+// network connection
+
+use serde_derive::{Deserialize, Serialize};
+use simple_error::bail;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::block;
+use super::config;
+use super::responder;
+use super::statistics;
+use super::worker;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub time: String,
+    pub message: String,
+}
+
+pub fn append(path: &str, message: &str) -> MyResult<()> {
+    let m = Message {
+        time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+        message: message.to_string(),
+    };
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = serde_json::to_vec(&m)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
+pub fn read(path: &str) -> MyResult<Vec<Message>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) => bail!("record: {} error: {}", path, e),
+    };
+    let mut messages = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(m) => messages.push(m),
+            Err(e) => bail!("record: {}:{} error: {}", path, n + 1, e),
+        }
+    }
+    Ok(messages)
+}
+
+// the delay before each message at the given speed, 0 is no delay
+fn delays(messages: &[Message], speed: f64) -> MyResult<Vec<Duration>> {
+    let mut delays = Vec::new();
+    let mut previous = None;
+    for m in messages {
+        let time = match chrono::DateTime::parse_from_rfc3339(&m.time) {
+            Ok(t) => t,
+            Err(e) => bail!("record: time: {} error: {}", m.time, e),
+        };
+        let gap = (time - previous.unwrap_or(time))
+            .to_std()
+            .unwrap_or_default();
+        previous = Some(time);
+        if speed > 0.0 {
+            delays.push(gap.div_f64(speed));
+        } else {
+            delays.push(Duration::ZERO);
+        }
+    }
+    Ok(delays)
+}
+
+// feed the recorded jobs to the workers of a connection, found nonces
+// are logged but not submitted; the last job is hashed for tail seconds
+pub fn replay(
+    cfg: &config::Configuration,
+    connection: &config::Connection,
+    path: &str,
+    speed: f64,
+    tail: u64,
+) -> MyResult<String> {
+    let messages = read(path)?;
+    let set = connection.number;
+    let stats = Arc::new(statistics::Connection::new(set));
+    let (tx, rx) = std::sync::mpsc::channel::<responder::Response>();
+    let mut settings = worker::Settings {
+        verify_digest: cfg.verify_digest,
+        interleave: connection.interleave,
+        ..Default::default()
+    };
+    settings.set_params(set, &connection.argon2)?;
+    let mut pool = worker::create_workers(
+        set,
+        connection.workers,
+        tx,
+        cfg.hooks.clone(),
+        stats.clone(),
+        settings,
+    );
+
+    let found_stats = stats.clone();
+    std::thread::spawn(move || {
+        for response in rx {
+            found_stats.found.fetch_add(1, Ordering::Relaxed);
+            let nonce = block::nonce_hex(block::packed_nonce(&response.packed));
+            log::info!(
+                connection = set,
+                job = response.job.as_str(),
+                nonce:% = nonce;
+                "C{}: replay: job: {}  nonce: {}  not submitted",
+                set,
+                response.job,
+                nonce
+            );
+        }
+    });
+
+    log::info!(
+        "C{}: replay: {} messages from: {}",
+        set,
+        messages.len(),
+        path
+    );
+    for (m, delay) in messages.iter().zip(delays(&messages, speed)?) {
+        std::thread::sleep(delay);
+        match responder::send_job(set, &m.message, &mut pool, &cfg.hooks) {
+            Ok(_) => {
+                stats.jobs.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => log::error!("C{}: replay: {}  send_job error: {}", set, m.time, e),
+        }
+    }
+    std::thread::sleep(Duration::from_secs(tail));
+
+    // stop the workers, a hash in progress is not counted
+    pool.resize(0)?;
+    Ok(format!(
+        "messages: {}  jobs: {}  hashes: {}  found: {}\n",
+        messages.len(),
+        stats.jobs.load(Ordering::Relaxed),
+        stats.hashes.load(Ordering::Relaxed),
+        stats.found.load(Ordering::Relaxed)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let path = std::env::temp_dir().join(format!("mt-recorder-record-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        append(path, r#"{"job":"01"}"#).unwrap();
+        append(path, "not json").unwrap();
+        let messages = read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].message, "not json");
+
+        let at = |s: &str| Message {
+            time: s.to_string(),
+            message: String::new(),
+        };
+        let messages = vec![
+            at("2024-01-01T10:00:00Z"),
+            at("2024-01-01T10:00:30Z"),
+            at("2024-01-01T10:01:30Z"),
+        ];
+        assert_eq!(
+            delays(&messages, 1.0).unwrap(),
+            vec![
+                Duration::ZERO,
+                Duration::from_secs(30),
+                Duration::from_secs(60)
+            ]
+        );
+        assert_eq!(delays(&messages, 10.0).unwrap()[2], Duration::from_secs(6));
+        assert_eq!(delays(&messages, 0.0).unwrap()[2], Duration::ZERO);
+        assert!(delays(&[at("yesterday")], 1.0).is_err());
+    }
+}
@@ -0,0 +1,417 @@
+// reference.rs
+
+// plain scalar Argon2d and BLAKE2b (RFC 9106, RFC 7693) sharing no
+// code with the hashing library, to check found nonces independently
+// of any SIMD path; slow, so only used on the rare found nonce
+
+use super::block;
+
+const BLOCK_WORDS: usize = 128;
+
+// words of a 1 KiB Argon2 memory block
+type Block = [u64; BLOCK_WORDS];
+
+const SYNC_POINTS: u32 = 4;
+
+const ARGON2_VERSION: u32 = 0x13;
+
+// Argon2d
+const ARGON2_TYPE: u32 = 0;
+
+pub struct Params<'a> {
+    pub memory: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+    pub secret: &'a [u8],
+    pub ad: &'a [u8],
+    pub length: u32,
+}
+
+// only Argon2d version 0x13 is implemented
+pub fn supports(p: &block::Params) -> bool {
+    p.variant == argon2::Variant::Argon2d && p.version == argon2::Version::Version13
+}
+
+// same as block::digest for supported parameters
+pub fn digest(data: &[u8], p: &block::Params) -> Vec<u8> {
+    argon2d(
+        data,
+        data,
+        &Params {
+            memory: p.memory,
+            iterations: p.iterations,
+            lanes: p.lanes,
+            secret: &[],
+            ad: &[],
+            length: 32,
+        },
+    )
+}
+
+pub fn argon2d(password: &[u8], salt: &[u8], p: &Params) -> Vec<u8> {
+    let mut h = Blake2b::new(64);
+    for n in [
+        p.lanes,
+        p.length,
+        p.memory,
+        p.iterations,
+        ARGON2_VERSION,
+        ARGON2_TYPE,
+    ] {
+        h.update(&n.to_le_bytes());
+    }
+    for s in [password, salt, p.secret, p.ad] {
+        h.update(&(s.len() as u32).to_le_bytes());
+        h.update(s);
+    }
+    let h0 = h.finalize();
+
+    let lanes = p.lanes as usize;
+    let segment = (p.memory / (SYNC_POINTS * p.lanes)) as usize;
+    let columns = segment * SYNC_POINTS as usize;
+    let mut memory: Vec<Block> = vec![[0; BLOCK_WORDS]; lanes * columns];
+
+    for l in 0..lanes {
+        for j in 0..2 {
+            let mut seed = h0.clone();
+            seed.extend_from_slice(&(j as u32).to_le_bytes());
+            seed.extend_from_slice(&(l as u32).to_le_bytes());
+            let bytes = variable_hash(&seed, 1024);
+            let b = &mut memory[l * columns + j];
+            for (w, c) in b.iter_mut().zip(bytes.chunks_exact(8)) {
+                *w = u64::from_le_bytes(c.try_into().unwrap());
+            }
+        }
+    }
+
+    for pass in 0..p.iterations as usize {
+        for slice in 0..SYNC_POINTS as usize {
+            for l in 0..lanes {
+                for i in 0..segment {
+                    let j = slice * segment + i;
+                    if pass == 0 && j < 2 {
+                        continue;
+                    }
+                    let previous = l * columns + if j == 0 { columns - 1 } else { j - 1 };
+                    let random = memory[previous][0];
+                    let (j1, j2) = (random & 0xffffffff, random >> 32);
+
+                    let ref_lane = if pass == 0 && slice == 0 {
+                        l
+                    } else {
+                        (j2 % p.lanes as u64) as usize
+                    };
+                    let same = ref_lane == l;
+                    let area = if pass == 0 {
+                        if same {
+                            j - 1
+                        } else {
+                            slice * segment - usize::from(i == 0)
+                        }
+                    } else if same {
+                        columns - segment + i - 1
+                    } else {
+                        columns - segment - usize::from(i == 0)
+                    };
+                    let x = (j1 * j1) >> 32;
+                    let y = (area as u64 * x) >> 32;
+                    let relative = area as u64 - 1 - y;
+                    let start = if pass == 0 || slice == SYNC_POINTS as usize - 1 {
+                        0
+                    } else {
+                        (slice + 1) * segment
+                    };
+                    let reference = ref_lane * columns + (start + relative as usize) % columns;
+
+                    let next = compress(&memory[previous], &memory[reference]);
+                    let b = &mut memory[l * columns + j];
+                    if pass == 0 {
+                        *b = next;
+                    } else {
+                        for (w, n) in b.iter_mut().zip(next.iter()) {
+                            *w ^= n;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut last = memory[columns - 1];
+    for l in 1..lanes {
+        for (w, n) in last
+            .iter_mut()
+            .zip(memory[l * columns + columns - 1].iter())
+        {
+            *w ^= n;
+        }
+    }
+    let bytes: Vec<u8> = last.iter().flat_map(|w| w.to_le_bytes()).collect();
+    variable_hash(&bytes, p.length as usize)
+}
+
+// the Argon2 compression function G
+fn compress(x: &Block, y: &Block) -> Block {
+    let mut r = [0; BLOCK_WORDS];
+    for i in 0..BLOCK_WORDS {
+        r[i] = x[i] ^ y[i];
+    }
+    let mut q = r;
+    for row in 0..8 {
+        let mut v = [0; 16];
+        v.copy_from_slice(&q[row * 16..row * 16 + 16]);
+        permute(&mut v);
+        q[row * 16..row * 16 + 16].copy_from_slice(&v);
+    }
+    for column in 0..8 {
+        let mut v = [0; 16];
+        for k in 0..8 {
+            v[2 * k] = q[2 * column + 16 * k];
+            v[2 * k + 1] = q[2 * column + 16 * k + 1];
+        }
+        permute(&mut v);
+        for k in 0..8 {
+            q[2 * column + 16 * k] = v[2 * k];
+            q[2 * column + 16 * k + 1] = v[2 * k + 1];
+        }
+    }
+    for i in 0..BLOCK_WORDS {
+        q[i] ^= r[i];
+    }
+    q
+}
+
+fn permute(v: &mut [u64; 16]) {
+    mix(v, 0, 4, 8, 12);
+    mix(v, 1, 5, 9, 13);
+    mix(v, 2, 6, 10, 14);
+    mix(v, 3, 7, 11, 15);
+    mix(v, 0, 5, 10, 15);
+    mix(v, 1, 6, 11, 12);
+    mix(v, 2, 7, 8, 13);
+    mix(v, 3, 4, 9, 14);
+}
+
+// BLAKE2b G with the multiplications added by Argon2
+fn mix(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize) {
+    let fblamka = |x: u64, y: u64| {
+        let m = (x & 0xffffffff) * (y & 0xffffffff);
+        x.wrapping_add(y).wrapping_add(m.wrapping_mul(2))
+    };
+    v[a] = fblamka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = fblamka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = fblamka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = fblamka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+// H' of RFC 9106: BLAKE2b extended to any output length
+fn variable_hash(input: &[u8], length: usize) -> Vec<u8> {
+    let prefix = (length as u32).to_le_bytes();
+    if length <= 64 {
+        let mut h = Blake2b::new(length);
+        h.update(&prefix);
+        h.update(input);
+        return h.finalize();
+    }
+    let mut h = Blake2b::new(64);
+    h.update(&prefix);
+    h.update(input);
+    let mut v = h.finalize();
+    let mut out = Vec::with_capacity(length);
+    loop {
+        out.extend_from_slice(&v[..32]);
+        let remaining = length - out.len();
+        let mut h = Blake2b::new(remaining.min(64));
+        h.update(&v);
+        v = h.finalize();
+        if remaining <= 64 {
+            out.extend_from_slice(&v);
+            break;
+        }
+    }
+    out
+}
+
+const IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+// unkeyed BLAKE2b with 1 to 64 bytes of output
+struct Blake2b {
+    h: [u64; 8],
+    buffer: Vec<u8>,
+    counter: u128,
+    length: usize,
+}
+
+impl Blake2b {
+    fn new(length: usize) -> Self {
+        let mut h = IV;
+        h[0] ^= 0x01010000 ^ length as u64;
+        Blake2b {
+            h,
+            buffer: Vec::with_capacity(128),
+            counter: 0,
+            length,
+        }
+    }
+
+    // the last block is kept back since it is compressed with the
+    // final flag set
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.buffer.len() == 128 {
+                self.counter += 128;
+                let block = std::mem::take(&mut self.buffer);
+                self.compress(&block, false);
+                self.buffer = block;
+                self.buffer.clear();
+            }
+            let n = (128 - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+    }
+
+    fn finalize(mut self) -> Vec<u8> {
+        self.counter += self.buffer.len() as u128;
+        let mut block = std::mem::take(&mut self.buffer);
+        block.resize(128, 0);
+        self.compress(&block, true);
+        let bytes: Vec<u8> = self.h.iter().flat_map(|w| w.to_le_bytes()).collect();
+        bytes[..self.length].to_vec()
+    }
+
+    fn compress(&mut self, block: &[u8], last: bool) {
+        let mut m = [0u64; 16];
+        for (w, c) in m.iter_mut().zip(block.chunks_exact(8)) {
+            *w = u64::from_le_bytes(c.try_into().unwrap());
+        }
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&IV);
+        v[12] ^= self.counter as u64;
+        v[13] ^= (self.counter >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+        for round in 0..12 {
+            let s = &SIGMA[round % 10];
+            let g = |v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64| {
+                v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+                v[d] = (v[d] ^ v[a]).rotate_right(32);
+                v[c] = v[c].wrapping_add(v[d]);
+                v[b] = (v[b] ^ v[c]).rotate_right(24);
+                v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+                v[d] = (v[d] ^ v[a]).rotate_right(16);
+                v[c] = v[c].wrapping_add(v[d]);
+                v[b] = (v[b] ^ v[c]).rotate_right(63);
+            };
+            g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+            g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+            g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+            g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+            g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+            g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+            g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+            g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference() {
+        // RFC 7693 appendix A
+        let mut h = Blake2b::new(64);
+        h.update(b"abc");
+        assert_eq!(
+            hex::encode(h.finalize()),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+        );
+
+        // RFC 9106 section 5.1
+        let tag = argon2d(
+            &[0x01; 32],
+            &[0x02; 16],
+            &Params {
+                memory: 32,
+                iterations: 3,
+                lanes: 4,
+                secret: &[0x03; 8],
+                ad: &[0x04; 12],
+                length: 32,
+            },
+        );
+        assert_eq!(
+            hex::encode(tag),
+            "512b391b6f1162975371d30919734294f868e3be3984f3c1a13a4db9fabe4acb"
+        );
+
+        // agrees with the hashing library on a small memory size
+        let data = b"0123456789abcdef";
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2d,
+            version: argon2::Version::Version13,
+            mem_cost: 64,
+            time_cost: 4,
+            lanes: 1,
+            secret: &[],
+            ad: &[],
+            hash_length: 32,
+        };
+        let params = Params {
+            memory: 64,
+            iterations: 4,
+            lanes: 1,
+            secret: &[],
+            ad: &[],
+            length: 32,
+        };
+        assert_eq!(
+            argon2d(data, data, &params),
+            argon2::hash_raw(data, data, &config).unwrap()
+        );
+
+        // and with more lanes through the block parameters
+        let p = block::Params {
+            memory: 256,
+            iterations: 2,
+            lanes: 4,
+            ..block::LIVE
+        };
+        assert!(supports(&p));
+        assert_eq!(digest(data, &p), block::digest(data, &p));
+    }
+}
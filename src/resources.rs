@@ -0,0 +1,343 @@
+// resources.rs
+
+// CPU and memory available to this process, taking cgroup limits into
+// account, to size the workers and check they fit in memory
+
+use simple_error::bail;
+use std::path::{Path, PathBuf};
+
+use super::config;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// bytes of Argon2 memory each worker of a connection needs, one
+// memory for each interleaved digest; its self test and verify_digest
+// run between batches and need no more
+pub fn worker_memory(c: &config::Connection) -> u64 {
+    batch_memory(c.argon2.memory, c.interleave)
+}
+
+// bytes for a batch of interleaved digests, each of memory KiB
+pub fn batch_memory(memory: u32, interleave: u32) -> u64 {
+    memory as u64 * 1024 * interleave.max(1) as u64
+}
+
+// memory left for everything else when sizing automatically
+const HEADROOM: f64 = 0.1;
+
+#[derive(Debug, PartialEq)]
+pub struct Limits {
+    pub cpus: u32,
+    pub memory: u64,
+}
+
+pub fn limits() -> Limits {
+    let online = match std::thread::available_parallelism() {
+        Ok(n) => n.get() as u32,
+        Err(_) => 1,
+    };
+    limits_from(Path::new("/"), online)
+}
+
+// root is "/" except for tests
+fn limits_from(root: &Path, online: u32) -> Limits {
+    let cgroup = cgroup_directory(root);
+
+    let cpus = match cpu_quota(root, &cgroup) {
+        Some(quota) => (quota.ceil() as u32).clamp(1, online),
+        None => online,
+    };
+
+    let mut memory = read_meminfo(root).unwrap_or(u64::MAX);
+    if let Some(m) = cgroup_memory(root, &cgroup) {
+        memory = memory.min(m);
+    }
+
+    Limits { cpus, memory }
+}
+
+// the unified hierarchy directory of this process from
+// /proc/self/cgroup: "0::/system.slice/mt-recorder.service"
+fn cgroup_directory(root: &Path) -> PathBuf {
+    let base = root.join("sys/fs/cgroup");
+    if let Ok(s) = std::fs::read_to_string(root.join("proc/self/cgroup")) {
+        if let Some(path) = s.lines().find_map(|l| l.strip_prefix("0::")) {
+            let dir = base.join(path.trim_start_matches('/'));
+            if dir.is_dir() {
+                return dir;
+            }
+        }
+    }
+    base
+}
+
+// v2 cpu.max: "max 100000" or "200000 100000", v1 cfs quota and period
+fn cpu_quota(root: &Path, cgroup: &Path) -> Option<f64> {
+    if let Ok(s) = std::fs::read_to_string(cgroup.join("cpu.max")) {
+        let mut f = s.split_whitespace();
+        let quota = f.next()?.parse::<f64>().ok()?;
+        let period = f.next()?.parse::<f64>().ok()?;
+        return Some(quota / period);
+    }
+    let v1 = root.join("sys/fs/cgroup/cpu");
+    let quota = read_number(&v1.join("cpu.cfs_quota_us"))?;
+    let period = read_number(&v1.join("cpu.cfs_period_us"))?;
+    if quota <= 0 || period <= 0 {
+        return None;
+    }
+    Some(quota as f64 / period as f64)
+}
+
+// remaining memory in the cgroup: v2 memory.max less memory.current,
+// v1 limit_in_bytes less usage_in_bytes
+fn cgroup_memory(root: &Path, cgroup: &Path) -> Option<u64> {
+    let (limit, usage) = match read_number(&cgroup.join("memory.max")) {
+        Some(limit) => (limit, read_number(&cgroup.join("memory.current"))?),
+        None => {
+            let v1 = root.join("sys/fs/cgroup/memory");
+            (
+                read_number(&v1.join("memory.limit_in_bytes"))?,
+                read_number(&v1.join("memory.usage_in_bytes"))?,
+            )
+        }
+    };
+    if limit <= 0 {
+        return None;
+    }
+    Some((limit - usage).max(0) as u64)
+}
+
+// MemAvailable in /proc/meminfo is in kB
+fn read_meminfo(root: &Path) -> Option<u64> {
+    let s = std::fs::read_to_string(root.join("proc/meminfo")).ok()?;
+    let line = s.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+// "max" and other non numbers are no limit
+fn read_number(path: &Path) -> Option<i64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+// share the CPUs, limited by memory, between the connections with
+// workers = "auto" after allowing for the fixed worker counts
+pub fn resolve_workers(cfg: &mut config::Configuration, limits: &Limits) {
+    let enabled = cfg
+        .connections
+        .iter_mut()
+        .filter(|c| c.enable && !c.public_key.is_empty())
+        .collect();
+    resolve(enabled, limits);
+}
+
+// the one connection a subcommand hashes with, enabled or not, sized
+// and checked as if it were the only one
+pub fn size_connection(
+    c: &mut config::Connection,
+    memory_check: &str,
+    limits: &Limits,
+) -> MyResult<Option<String>> {
+    resolve(vec![&mut *c], limits);
+    check(&[(c.workers, worker_memory(c))], memory_check, limits)
+}
+
+fn resolve(mut connections: Vec<&mut config::Connection>, limits: &Limits) {
+    let (fixed, fixed_memory) =
+        connections
+            .iter()
+            .filter(|c| !c.auto_workers)
+            .fold((0, 0), |(n, m), c| {
+                (
+                    n + c.workers as u64,
+                    m + c.workers as u64 * worker_memory(c),
+                )
+            });
+    let mut auto: Vec<&mut &mut config::Connection> =
+        connections.iter_mut().filter(|c| c.auto_workers).collect();
+    if auto.is_empty() {
+        return;
+    }
+
+    // sized for the largest digest memory of the automatic connections
+    let per_worker = auto
+        .iter()
+        .map(|c| worker_memory(c))
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let memory = ((limits.memory as f64 * (1.0 - HEADROOM)) as u64).saturating_sub(fixed_memory);
+    let total = (limits.cpus as u64)
+        .saturating_sub(fixed)
+        .min(memory / per_worker);
+    let n = auto.len() as u64;
+    for (i, c) in auto.iter_mut().enumerate() {
+        let share = total / n + u64::from((i as u64) < total % n);
+        c.workers = share.max(1) as u32;
+    }
+}
+
+// whether the enabled workers fit in the available memory, on
+// "refuse" an overcommit is an error and on "warn" it is returned
+pub fn check_memory(cfg: &config::Configuration, limits: &Limits) -> MyResult<Option<String>> {
+    let enabled: Vec<(u32, u64)> = cfg
+        .connections
+        .iter()
+        .filter(|c| c.enable && !c.public_key.is_empty())
+        .map(|c| (c.workers, worker_memory(c)))
+        .collect();
+    check(&enabled, &cfg.memory_check, limits)
+}
+
+// the same for the worker count and bytes per worker of each running
+// connection, as when workers are added while running
+pub fn check(
+    workers: &[(u32, u64)],
+    memory_check: &str,
+    limits: &Limits,
+) -> MyResult<Option<String>> {
+    match memory_check {
+        "off" => return Ok(None),
+        "warn" | "refuse" => {}
+        other => bail!("invalid memory_check: {}", other),
+    }
+    let required: u64 = workers.iter().map(|(n, m)| *n as u64 * m).sum();
+    let workers: u64 = workers.iter().map(|(n, _)| *n as u64).sum();
+    if required <= limits.memory {
+        return Ok(None);
+    }
+    let message = format!(
+        "{} workers need: {} MiB but only: {} MiB is available",
+        workers,
+        required >> 20,
+        limits.memory >> 20
+    );
+    if memory_check == "warn" {
+        return Ok(Some(message));
+    }
+    bail!("{}, set memory_check = \"warn\" to run anyway", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let root =
+            std::env::temp_dir().join(format!("mt-recorder-resources-{}", std::process::id()));
+        let cgroup = root.join("sys/fs/cgroup/system.slice/mt.service");
+        std::fs::create_dir_all(root.join("proc/self")).unwrap();
+        std::fs::create_dir_all(&cgroup).unwrap();
+        std::fs::write(
+            root.join("proc/meminfo"),
+            "MemTotal:       16000000 kB\nMemAvailable:    4194304 kB\n",
+        )
+        .unwrap();
+
+        // no cgroup information
+        assert_eq!(
+            limits_from(&root, 8),
+            Limits {
+                cpus: 8,
+                memory: 4 << 30
+            }
+        );
+
+        std::fs::write(
+            root.join("proc/self/cgroup"),
+            "0::/system.slice/mt.service\n",
+        )
+        .unwrap();
+        std::fs::write(cgroup.join("cpu.max"), "250000 100000\n").unwrap();
+        std::fs::write(cgroup.join("memory.max"), "1073741824\n").unwrap();
+        std::fs::write(cgroup.join("memory.current"), "268435456\n").unwrap();
+        assert_eq!(
+            limits_from(&root, 8),
+            Limits {
+                cpus: 3,
+                memory: 768 << 20
+            }
+        );
+
+        std::fs::write(cgroup.join("cpu.max"), "max 100000\n").unwrap();
+        std::fs::write(cgroup.join("memory.max"), "max\n").unwrap();
+        assert_eq!(
+            limits_from(&root, 8),
+            Limits {
+                cpus: 8,
+                memory: 4 << 30
+            }
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_workers() {
+        let cfg = config::read(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/mt-recorder.conf.sample"),
+            false,
+        );
+        let mut cfg = cfg.unwrap();
+        for c in cfg.connections.iter_mut() {
+            c.enable = true;
+            c.public_key = "00".to_string();
+        }
+        cfg.connections[0].auto_workers = true;
+        cfg.connections[1].auto_workers = true;
+        cfg.connections[2].workers = 2;
+
+        // 8 CPUs less 2 fixed, shared between two
+        let mut limits = Limits {
+            cpus: 8,
+            memory: 64 << 30,
+        };
+        resolve_workers(&mut cfg, &limits);
+        assert_eq!(cfg.connections[0].workers, 3);
+        assert_eq!(cfg.connections[1].workers, 3);
+        assert_eq!(check_memory(&cfg, &limits).unwrap(), None);
+
+        // memory for 5 workers, 90% of 768 MiB
+        limits.memory = 768 << 20;
+        resolve_workers(&mut cfg, &limits);
+        assert_eq!(cfg.connections[0].workers, 2);
+        assert_eq!(cfg.connections[1].workers, 1);
+        assert!(check_memory(&cfg, &limits).is_ok());
+
+        limits.memory = 256 << 20;
+        assert!(check_memory(&cfg, &limits).is_err());
+
+        cfg.memory_check = "warn".to_string();
+        assert!(check_memory(&cfg, &limits).unwrap().is_some());
+
+        // lighter digests fit more workers
+        for c in cfg.connections.iter_mut() {
+            c.argon2.memory = 1 << 10;
+        }
+        resolve_workers(&mut cfg, &limits);
+        assert_eq!(cfg.connections[0].workers, 3);
+        assert_eq!(cfg.connections[1].workers, 3);
+        assert_eq!(check_memory(&cfg, &limits).unwrap(), None);
+
+        // interleaving needs a memory for each digest
+        cfg.connections[2].interleave = 2;
+        assert_eq!(worker_memory(&cfg.connections[2]), 2 << 20);
+
+        // a subcommand's connection is sized alone, even when disabled
+        cfg.connections[0].enable = false;
+        let c = &mut cfg.connections[0];
+        assert_eq!(size_connection(c, "refuse", &limits).unwrap(), None);
+        assert_eq!(c.workers, 8);
+        c.auto_workers = false;
+        limits.memory = 4 << 20;
+        assert!(size_connection(c, "refuse", &limits).is_err());
+
+        // workers added while running are checked against the total
+        assert_eq!(
+            check(&[(2, 1 << 20), (1, 2 << 20)], "refuse", &limits).unwrap(),
+            None
+        );
+        assert!(check(&[(4, 1 << 20), (1, 2 << 20)], "refuse", &limits).is_err());
+    }
+}
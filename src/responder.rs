@@ -10,21 +10,23 @@ base64_serde_type!(Base64Standard, base64::engine::general_purpose::STANDARD);
 type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
 
 use super::block;
+use super::hooks;
+use super::worker;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Job {
     #[serde(rename = "job", alias = "Job")]
-    job: String,
+    pub job: String,
 
     #[serde(rename = "header", alias = "Header")]
-    header: block::Header,
+    pub header: block::Header,
 
     #[serde(rename = "txZero", alias = "TxZero", with = "Base64Standard")]
-    tx_zero: Vec<u8>,
+    pub tx_zero: Vec<u8>,
 
     //#[serde(rename = "txIds", with = "hex_serde")]
     #[serde(rename = "txIds", alias = "TxIds")]
-    tx_ids: Vec<String>,
+    pub tx_ids: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,37 +39,58 @@ pub struct Response {
 
     #[serde(rename = "packed", with = "Base64Standard")]
     pub packed: Vec<u8>,
+
+    // for the journal, not sent
+    #[serde(skip)]
+    pub header: bytes::Bytes,
+    #[serde(skip)]
+    pub digest: Vec<u8>,
+    #[serde(skip)]
+    pub worker: u32,
 }
 
-pub fn send_job(
-    set: i64,
-    s: &str,
-    txs: &mut Vec<spmc::Sender<(bytes::Bytes, u64, std::string::String)>>,
-) -> MyResult<()> {
+pub fn send_job(set: i64, s: &str, pool: &mut worker::Pool, hooks: &hooks::Hooks) -> MyResult<()> {
     let p: Job = serde_json::from_str(s)?;
 
     // debugging
-    log::info!("C{}: job: {}  block: {}", set, p.job, p.header.number);
+    log::info!(
+        connection = set,
+        job = p.job.as_str(),
+        block = p.header.number;
+        "C{}: job: {}  block: {}",
+        set,
+        p.job,
+        p.header.number
+    );
     log::trace!("C{}: tx_0: {:02x?}", set, p.tx_zero);
     log::debug!("C{}: header: {:?}", set, p.header);
 
+    let accept = hooks.on_job(|t| {
+        t.set("connection", set)?;
+        t.set("job", p.job.as_str())?;
+        t.set("block", p.header.number)?;
+        t.set("version", p.header.version)?;
+        t.set("transaction_count", p.header.transaction_count)?;
+        t.set("previous_block", hex::encode(p.header.previous_block))?;
+        t.set("merkle_root", hex::encode(p.header.merkle_root))?;
+        t.set("timestamp", p.header.timestamp)?;
+        t.set("difficulty", hex::encode(p.header.difficulty))?;
+        t.set("tx_ids", p.tx_ids.clone())?;
+        t.set("workers", pool.len())
+    });
+    if !accept {
+        log::info!("C{}: job: {}  rejected by on_job hook", set, p.job);
+        return Ok(());
+    }
+
     let h = p.header;
 
-    let mut nnn = u64::from_le_bytes(h.nonce);
+    let nonce = u64::from_le_bytes(h.nonce);
 
     let buf = bytes::Bytes::from(h);
     if buf.len() != 100 - 8 {
         bail!("block header wrong");
     }
 
-    let mut w = 1;
-    for tx in txs.iter_mut() {
-        log::info!("C{}: W{}: nonce: {:016x}", set, w, nnn);
-        let blk = buf.clone();
-        tx.send((blk, nnn, p.job.clone()))?;
-        nnn += 0x100000000;
-        w += 1;
-    }
-
-    Ok(())
+    pool.send(buf, nonce, &p.job)
 }
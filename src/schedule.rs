@@ -0,0 +1,291 @@
+// schedule.rs
+
+// hashing windows in local time, hashing is allowed while any rule
+// matches and always when there are no rules:
+//
+//   "mon-fri 18:00-08:00"  the listed days, time of day range which
+//                          may wrap past midnight into the next day
+//   "sat,sun"              the whole of the listed days
+//   "* 0-7,18-23 * * 1-5"  cron style: minute hour day month weekday
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use simple_error::bail;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// names for the cron weekday field, sunday is 0
+const CRON_DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Debug, Default)]
+pub struct Schedule(Vec<Rule>);
+
+#[derive(Debug)]
+enum Rule {
+    // bit 0 = monday, minutes of the day: start..end
+    Window {
+        days: u64,
+        start: u32,
+        end: u32,
+    },
+    // bit masks, weekday bit 0 = sunday as for cron
+    Cron {
+        minute: u64,
+        hour: u64,
+        day: u64,
+        month: u64,
+        weekday: u64,
+        any_day: bool,
+        any_weekday: bool,
+    },
+}
+
+impl Schedule {
+    pub fn parse(rules: &[String]) -> MyResult<Self> {
+        let mut schedule = Schedule::default();
+        for rule in rules {
+            let fields: Vec<&str> = rule.split_whitespace().collect();
+            let r = match fields.as_slice() {
+                [days] => Rule::Window {
+                    days: days_mask(days)?,
+                    start: 0,
+                    end: 24 * 60,
+                },
+                [days, times] => {
+                    let (start, end) = time_range(times)?;
+                    Rule::Window {
+                        days: days_mask(days)?,
+                        start,
+                        end,
+                    }
+                }
+                [minute, hour, day, month, weekday] => Rule::Cron {
+                    minute: field(minute, 0, 59, &[])?,
+                    hour: field(hour, 0, 23, &[])?,
+                    day: field(day, 1, 31, &[])?,
+                    month: field(month, 1, 12, &[])?,
+                    // 7 is also sunday
+                    weekday: {
+                        let m = field(weekday, 0, 7, &CRON_DAYS)?;
+                        (m | m >> 7) & 0x7f
+                    },
+                    any_day: *day == "*",
+                    any_weekday: *weekday == "*",
+                },
+                _ => bail!("invalid schedule: {}", rule),
+            };
+            schedule.0.push(r);
+        }
+        Ok(schedule)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn active(&self, t: &NaiveDateTime) -> bool {
+        self.is_empty() || self.0.iter().any(|r| r.matches(t))
+    }
+}
+
+impl Rule {
+    fn matches(&self, t: &NaiveDateTime) -> bool {
+        match self {
+            Rule::Window { days, start, end } => {
+                let minutes = t.hour() * 60 + t.minute();
+                let today = t.weekday().num_days_from_monday();
+                if start < end {
+                    bit(*days, today) && *start <= minutes && minutes < *end
+                } else if minutes >= *start {
+                    bit(*days, today)
+                } else {
+                    // after midnight the window opened the day before
+                    minutes < *end && bit(*days, (today + 6) % 7)
+                }
+            }
+            Rule::Cron {
+                minute,
+                hour,
+                day,
+                month,
+                weekday,
+                any_day,
+                any_weekday,
+            } => {
+                let d = bit(*day, t.day());
+                let w = bit(*weekday, t.weekday().num_days_from_sunday());
+                // as cron: when both are restricted either one matches
+                let date = match (any_day, any_weekday) {
+                    (false, false) => d || w,
+                    _ => d && w,
+                };
+                bit(*minute, t.minute()) && bit(*hour, t.hour()) && bit(*month, t.month()) && date
+            }
+        }
+    }
+}
+
+fn bit(mask: u64, n: u32) -> bool {
+    mask & (1 << n) != 0
+}
+
+// "*", "mon-fri", "sat,sun" or a wrapping range "fri-mon"
+fn days_mask(s: &str) -> MyResult<u64> {
+    if s == "*" {
+        return Ok(0x7f);
+    }
+    let day = |d: &str| match DAYS.iter().position(|x| *x == d.to_lowercase()) {
+        Some(n) => Ok(n as u32),
+        None => Err(format!("invalid day: {}", d)),
+    };
+    let mut mask = 0;
+    for item in s.split(',') {
+        match item.split_once('-') {
+            Some((a, b)) => {
+                let (a, b) = (day(a)?, day(b)?);
+                let mut n = a;
+                loop {
+                    mask |= 1 << n;
+                    if n == b {
+                        break;
+                    }
+                    n = (n + 1) % 7;
+                }
+            }
+            None => mask |= 1 << day(item)?,
+        }
+    }
+    Ok(mask)
+}
+
+// "HH:MM-HH:MM" as minutes of the day, the end may be 24:00
+fn time_range(s: &str) -> MyResult<(u32, u32)> {
+    let time = |t: &str| -> MyResult<u32> {
+        if let Some((h, m)) = t.split_once(':') {
+            if let (Ok(h), Ok(m)) = (h.parse::<u32>(), m.parse::<u32>()) {
+                if m < 60 && (h < 24 || (h == 24 && m == 0)) {
+                    return Ok(h * 60 + m);
+                }
+            }
+        }
+        bail!("invalid time: {}", t)
+    };
+    let (start, end) = match s.split_once('-') {
+        Some((a, b)) => (time(a)?, time(b)?),
+        None => bail!("invalid time range: {}", s),
+    };
+    if start == end {
+        bail!("empty time range: {}", s);
+    }
+    Ok((start, end))
+}
+
+// cron field: "*", "*/n", "a", "a-b", "a-b/n" and lists of these
+fn field(s: &str, min: u32, max: u32, names: &[&str]) -> MyResult<u64> {
+    let number = |v: &str| -> MyResult<u32> {
+        if let Some(n) = names.iter().position(|x| *x == v.to_lowercase()) {
+            return Ok(n as u32);
+        }
+        match v.parse::<u32>() {
+            Ok(n) if n >= min && n <= max => Ok(n),
+            _ => bail!("invalid schedule value: {}", v),
+        }
+    };
+    let mut mask = 0;
+    for item in s.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((r, n)) => match n.parse::<u32>() {
+                Ok(n) if n > 0 => (r, n),
+                _ => bail!("invalid schedule step: {}", item),
+            },
+            None => (item, 1),
+        };
+        let (a, b) = if range == "*" {
+            (min, max)
+        } else {
+            match range.split_once('-') {
+                Some((a, b)) => (number(a)?, number(b)?),
+                None => (number(range)?, number(range)?),
+            }
+        };
+        if a > b {
+            bail!("invalid schedule range: {}", item);
+        }
+        for n in (a..=b).step_by(step as usize) {
+            mask |= 1 << n;
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2024-01-01 is a monday
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn schedule(rules: &[&str]) -> Schedule {
+        let rules: Vec<String> = rules.iter().map(|s| s.to_string()).collect();
+        Schedule::parse(&rules).unwrap()
+    }
+
+    #[test]
+    fn test_schedule() {
+        assert!(schedule(&[]).active(&at(1, 12, 0)));
+
+        let s = schedule(&["mon-fri 18:00-08:00", "sat,sun"]);
+        assert!(!s.active(&at(1, 12, 0)));
+        assert!(s.active(&at(1, 18, 0)));
+        assert!(s.active(&at(2, 7, 59)));
+        assert!(!s.active(&at(2, 8, 0)));
+        assert!(s.active(&at(6, 12, 0)));
+        assert!(s.active(&at(7, 23, 59)));
+
+        // the hours after midnight belong to the day before
+        let s = schedule(&["mon 22:00-06:00"]);
+        assert!(s.active(&at(1, 22, 30)));
+        assert!(s.active(&at(2, 5, 59)));
+        assert!(!s.active(&at(2, 6, 0)));
+        assert!(!s.active(&at(1, 3, 0)));
+        assert!(!s.active(&at(2, 22, 30)));
+
+        let s = schedule(&["fri-mon 22:00-24:00"]);
+        assert!(s.active(&at(7, 22, 30)));
+        assert!(!s.active(&at(3, 22, 30)));
+
+        let s = schedule(&["*/15 0-7,18-23 * * mon-fri"]);
+        assert!(s.active(&at(1, 18, 45)));
+        assert!(!s.active(&at(1, 18, 46)));
+        assert!(!s.active(&at(1, 12, 0)));
+        assert!(!s.active(&at(6, 18, 45)));
+
+        // sunday as 0 or 7
+        assert!(schedule(&["* * * * 7"]).active(&at(7, 1, 2)));
+        assert!(schedule(&["* * * * 0"]).active(&at(7, 1, 2)));
+
+        // day of month or weekday when both are given
+        let s = schedule(&["* * 1 * sat"]);
+        assert!(s.active(&at(1, 0, 0)));
+        assert!(s.active(&at(6, 0, 0)));
+        assert!(!s.active(&at(2, 0, 0)));
+
+        for bad in [
+            "someday",
+            "mon 8:00",
+            "mon 08:00-08:00",
+            "* 24 * * *",
+            "* * *",
+            "*/0 * * * *",
+        ] {
+            assert!(Schedule::parse(&[bad.to_string()]).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// selftest.rs
+
+// known answer tests of the hashing backend, run once at startup and
+// then periodically by each worker to catch a bad CPU or a broken build
+
+use simple_error::bail;
+
+use super::block;
+use super::reference;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+pub struct Vector {
+    pub name: &'static str,
+    pub data: [u8; 100],
+    pub digest: [u8; 32],
+}
+
+// packed header with nonce and its digest: the live genesis block, then
+// made up headers with digests from the argon2 library, which shares no
+// code with the backends
+pub const VECTORS: &[Vector] = &[
+    Vector {
+        name: "live genesis",
+        data: [
+            0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x63, 0x8c, 0x15, 0x9c, 0x1f, 0x11, 0x3f, 0x70, 0xa9, 0x86, 0x6d, 0x9a,
+            0x9e, 0x52, 0xe9, 0xef, 0xe9, 0xb9, 0x92, 0x08, 0x48, 0xad, 0x1d, 0xf3, 0x48, 0x51,
+            0xbe, 0x8a, 0x56, 0x2a, 0x99, 0x8d, 0xb7, 0x9a, 0x80, 0x56, 0x00, 0x00, 0x00, 0x00,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x11, 0x5a, 0x38, 0xbf, 0x3a, 0x90,
+            0x9f, 0xe1,
+        ],
+        digest: [
+            0x5c, 0x93, 0xf7, 0x39, 0xeb, 0x01, 0xcd, 0xde, 0x30, 0x55, 0x79, 0xf0, 0x3c, 0xcf,
+            0xb3, 0x7a, 0x74, 0x29, 0x71, 0x31, 0x3f, 0xf9, 0x8d, 0x35, 0xb4, 0xc0, 0x7c, 0x43,
+            0x8f, 0xaf, 0x12, 0x00,
+        ],
+    },
+    Vector {
+        name: "live genesis header, nonce 0",
+        data: [
+            0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x63, 0x8c, 0x15, 0x9c, 0x1f, 0x11, 0x3f, 0x70, 0xa9, 0x86, 0x6d, 0x9a,
+            0x9e, 0x52, 0xe9, 0xef, 0xe9, 0xb9, 0x92, 0x08, 0x48, 0xad, 0x1d, 0xf3, 0x48, 0x51,
+            0xbe, 0x8a, 0x56, 0x2a, 0x99, 0x8d, 0xb7, 0x9a, 0x80, 0x56, 0x00, 0x00, 0x00, 0x00,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ],
+        digest: [
+            0x0a, 0x2a, 0xed, 0x52, 0xf2, 0xc8, 0xfc, 0x70, 0xd0, 0x50, 0x9f, 0xbd, 0xc1, 0x94,
+            0x40, 0x49, 0xc2, 0x19, 0x20, 0x18, 0x6d, 0x9a, 0xce, 0x56, 0x80, 0xb9, 0x16, 0xf2,
+            0xa3, 0x5f, 0x90, 0x03,
+        ],
+    },
+    Vector {
+        name: "empty block 2 header, nonce ffffffffffffffff",
+        data: [
+            0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff,
+        ],
+        digest: [
+            0x44, 0xc8, 0x14, 0x18, 0x04, 0x5f, 0x24, 0x65, 0xd2, 0xbb, 0x92, 0x61, 0xff, 0x58,
+            0x16, 0xaf, 0x99, 0x8b, 0xa3, 0x61, 0x98, 0x64, 0xca, 0x54, 0xd6, 0x48, 0x08, 0xe0,
+            0x51, 0x9c, 0xaa, 0x39,
+        ],
+    },
+];
+
+// the first vector that does not give the expected digest
+pub fn check() -> MyResult<()> {
+    check_params(&block::LIVE, 1)
+}
+
+// the vectors hashed as a worker hashes, interleaved and with its
+// parameters: known answers for bitmarkd's and otherwise the reference
+// digests, so a worker never needs more memory than its own batch
+pub fn check_params(params: &block::Params, interleave: u32) -> MyResult<()> {
+    // hashed by the library, there is nothing separate to compare with
+    if *params != block::LIVE && !reference::supports(params) {
+        return Ok(());
+    }
+    for v in VECTORS {
+        let batch = vec![&v.data[..]; interleave.max(1) as usize];
+        let digests = block::digests(&batch, params);
+        let expected = match *params == block::LIVE {
+            true => v.digest.to_vec(),
+            false => reference::digest(&v.data, params),
+        };
+        if let Some(digest) = digests.iter().find(|d| **d != expected) {
+            bail!(
+                "self test: {}: digest: {}  expected: {}",
+                v.name,
+                hex::encode(digest),
+                hex::encode(&expected)
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vectors() {
+        check().unwrap();
+
+        // the made up answers agree with a second implementation
+        for v in &VECTORS[1..] {
+            assert_eq!(
+                reference::digest(&v.data, &block::LIVE),
+                v.digest,
+                "{}",
+                v.name
+            );
+        }
+
+        let light = block::Params {
+            memory: 64,
+            iterations: 1,
+            ..block::LIVE
+        };
+        check_params(&light, 3).unwrap();
+    }
+}
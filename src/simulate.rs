@@ -0,0 +1,251 @@
+// simulate.rs
+
+// a standalone job source for testing and benchmarking the hashing
+// without bitmarkd: jobs with random hashes and incrementing block
+// numbers are fed to the workers and each found nonce is checked
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::block;
+use super::config;
+use super::reference;
+use super::responder;
+use super::statistics;
+use super::worker;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+pub struct Options {
+    pub difficulty: [u8; 8],
+    pub interval: u64,
+    pub duration: u64,
+    pub block: u64,
+    pub seed: u64,
+}
+
+#[derive(Debug, Default)]
+struct Checks {
+    valid: AtomicU64,
+    invalid: AtomicU64,
+    meets_target: AtomicU64,
+}
+
+// SplitMix64, enough for test data and repeatable from a seed
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn hash(&mut self) -> [u8; 32] {
+        let mut h = [0u8; 32];
+        for c in h.chunks_exact_mut(8) {
+            c.copy_from_slice(&self.next().to_le_bytes());
+        }
+        h
+    }
+}
+
+// 8 bytes in hex as in a job header
+pub fn parse_difficulty(s: &str) -> std::result::Result<[u8; 8], String> {
+    match hex::decode(s).ok().and_then(|b| b.try_into().ok()) {
+        Some(d) => Ok(d),
+        None => Err(format!("invalid difficulty: {} expected 16 hex digits", s)),
+    }
+}
+
+// job JSON in the form bitmarkd publishes
+fn job(random: &mut Random, id: u64, number: u64, difficulty: [u8; 8]) -> MyResult<String> {
+    let job = responder::Job {
+        job: format!("{:x}", id),
+        header: block::Header {
+            version: 1,
+            transaction_count: 1,
+            number,
+            previous_block: random.hash(),
+            merkle_root: random.hash(),
+            timestamp: chrono::Utc::now().timestamp() as u64,
+            difficulty,
+            nonce: random.next().to_le_bytes(),
+        },
+        tx_zero: Vec::new(),
+        tx_ids: Vec::new(),
+    };
+    Ok(serde_json::to_string(&job)?)
+}
+
+// recompute the digest of a found nonce apart from the hashing
+// backend, where the reference implements the parameters, and compare
+// with the target
+fn check(
+    response: &responder::Response,
+    target: &[u8; 32],
+    params: &block::Params,
+) -> (bool, bool) {
+    let mut packed = response.header.to_vec();
+    packed.extend_from_slice(&response.packed);
+    let digest = match reference::supports(params) {
+        true => reference::digest(&packed, params),
+        false => block::digest(&packed, params),
+    };
+    (digest == response.digest, block::satisfies(&digest, target))
+}
+
+// a new job every interval seconds until the duration, 0 is forever
+pub fn run(
+    cfg: &config::Configuration,
+    connection: &config::Connection,
+    options: &Options,
+) -> MyResult<String> {
+    let set = connection.number;
+    let stats = Arc::new(statistics::Connection::new(set));
+    let (tx, rx) = std::sync::mpsc::channel::<responder::Response>();
+    let target = block::difficulty_target(&options.difficulty);
+    let mut settings = worker::Settings {
+        verify_digest: cfg.verify_digest,
+        interleave: connection.interleave,
+        target: Some(target),
+        ..Default::default()
+    };
+    settings.set_params(set, &connection.argon2)?;
+    let params = settings.params;
+    let mut pool = worker::create_workers(
+        set,
+        connection.workers,
+        tx,
+        cfg.hooks.clone(),
+        stats.clone(),
+        settings,
+    );
+
+    let checks = Arc::new(Checks::default());
+    let found_stats = stats.clone();
+    let found_checks = checks.clone();
+    std::thread::spawn(move || {
+        for response in rx {
+            found_stats.found.fetch_add(1, Ordering::Relaxed);
+            let (valid, meets_target) = check(&response, &target, &params);
+            if valid {
+                found_checks.valid.fetch_add(1, Ordering::Relaxed);
+            } else {
+                found_checks.invalid.fetch_add(1, Ordering::Relaxed);
+            }
+            if meets_target {
+                found_checks.meets_target.fetch_add(1, Ordering::Relaxed);
+            }
+            let nonce = block::nonce_hex(block::packed_nonce(&response.packed));
+            if valid {
+                log::info!(
+                    connection = set,
+                    job = response.job.as_str(),
+                    nonce:% = nonce;
+                    "C{}: simulate: job: {}  nonce: {}  valid  meets target: {}",
+                    set,
+                    response.job,
+                    nonce,
+                    meets_target
+                );
+            } else {
+                log::error!(
+                    connection = set,
+                    job = response.job.as_str(),
+                    nonce:% = nonce;
+                    "C{}: simulate: job: {}  nonce: {}  invalid digest: {}",
+                    set,
+                    response.job,
+                    nonce,
+                    hex::encode(&response.digest)
+                );
+            }
+        }
+    });
+
+    let mut random = Random(options.seed);
+    let interval = Duration::from_secs(options.interval.max(1));
+    let duration = Duration::from_secs(options.duration);
+    let start = Instant::now();
+    let mut id = 1;
+    log::info!(
+        "C{}: simulate: difficulty: {}  interval: {:?}",
+        set,
+        hex::encode(options.difficulty),
+        interval
+    );
+    loop {
+        let s = job(&mut random, id, options.block + id - 1, options.difficulty)?;
+        responder::send_job(set, &s, &mut pool, &cfg.hooks)?;
+        stats.jobs.fetch_add(1, Ordering::Relaxed);
+        id += 1;
+
+        let next = start + interval * (id - 1) as u32;
+        let end = if options.duration == 0 {
+            next
+        } else {
+            next.min(start + duration)
+        };
+        std::thread::sleep(end.saturating_duration_since(Instant::now()));
+        if options.duration != 0 && start.elapsed() >= duration {
+            break;
+        }
+    }
+
+    pool.resize(0)?;
+    let hashes = stats.hashes.load(Ordering::Relaxed);
+    Ok(format!(
+        "jobs: {}  hashes: {}  rate: {:.2} H/s  found: {}  valid: {}  invalid: {}  meets target: {}\n",
+        stats.jobs.load(Ordering::Relaxed),
+        hashes,
+        hashes as f64 / start.elapsed().as_secs_f64(),
+        stats.found.load(Ordering::Relaxed),
+        checks.valid.load(Ordering::Relaxed),
+        checks.invalid.load(Ordering::Relaxed),
+        checks.meets_target.load(Ordering::Relaxed)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job() {
+        assert_eq!(
+            parse_difficulty("ffffffffffffff00").unwrap(),
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]
+        );
+        assert!(parse_difficulty("ff").is_err());
+
+        let mut random = Random(1);
+        let s = job(&mut random, 10, 500, [0xff; 8]).unwrap();
+        let j: responder::Job = serde_json::from_str(&s).unwrap();
+        assert_eq!(j.job, "a");
+        assert_eq!(j.header.number, 500);
+        assert_ne!(j.header.merkle_root, j.header.previous_block);
+
+        // same seed, same jobs
+        let mut again = Random(1);
+        assert_eq!(again.hash(), j.header.previous_block);
+
+        // the live genesis nonce checks out, a wrong digest does not
+        let v = &crate::selftest::VECTORS[0];
+        let mut response = responder::Response {
+            request: "block.nonce".to_string(),
+            job: "1".to_string(),
+            packed: v.data[92..].to_vec(),
+            header: bytes::Bytes::copy_from_slice(&v.data[..92]),
+            digest: v.digest.to_vec(),
+            worker: 1,
+        };
+        let target = block::difficulty_target(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+        assert_eq!(check(&response, &target, &block::LIVE), (true, true));
+        response.digest[0] ^= 1;
+        assert_eq!(check(&response, &target, &block::LIVE), (false, true));
+    }
+}
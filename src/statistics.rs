@@ -0,0 +1,210 @@
+// statistics.rs
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// shared counters and liveness for one connection, updated by the
+// poller, sender and worker threads; the flags are set by the control
+// socket
+#[derive(Debug, Default)]
+pub struct Connection {
+    pub number: i64,
+    pub subscribe_connected: AtomicBool,
+    pub request_connected: AtomicBool,
+    pub paused: AtomicBool,
+    pub off_schedule: AtomicBool,
+    pub reconnect_subscribe: AtomicBool,
+    pub reconnect_request: AtomicBool,
+    pub poller: Heartbeat,
+    pub sender: Heartbeat,
+    pub jobs: AtomicU64,
+    pub hashes: AtomicU64,
+    pub found: AtomicU64,
+    pub replies: AtomicU64,
+    pub restarts: AtomicU64,
+    pub hardware_errors: AtomicU64,
+    pub self_test_failures: AtomicU64,
+    pub workers: Mutex<Vec<Arc<Worker>>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Worker {
+    pub number: u32,
+    pub hashes: AtomicU64,
+    pub heartbeat: Heartbeat,
+    pub waiting: AtomicBool,
+    pub alive: AtomicBool,
+    pub restarts: AtomicU64,
+}
+
+// last time a thread showed progress, in milliseconds since the
+// process started so clock steps do not matter; 0 is never
+#[derive(Debug, Default)]
+pub struct Heartbeat(AtomicU64);
+
+// marks a worker as no longer alive when its thread ends, including
+// by panic since the drop runs during unwinding
+pub struct AliveGuard(pub Arc<Worker>);
+
+impl Connection {
+    pub fn new(number: i64) -> Self {
+        Connection {
+            number,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_worker(&self, number: u32) -> Arc<Worker> {
+        let worker = Arc::new(Worker {
+            number,
+            waiting: AtomicBool::new(true),
+            alive: AtomicBool::new(true),
+            ..Default::default()
+        });
+        worker.heartbeat.beat();
+        self.workers.lock().unwrap().push(worker.clone());
+        worker
+    }
+
+    pub fn worker(&self, number: u32) -> Option<Arc<Worker>> {
+        let workers = self.workers.lock().unwrap();
+        workers.iter().find(|w| w.number == number).cloned()
+    }
+
+    // a worker that was intentionally stopped or is being replaced
+    pub fn remove_worker(&self, number: u32) {
+        self.workers.lock().unwrap().retain(|w| w.number != number);
+    }
+
+    pub fn connected(&self) -> bool {
+        self.subscribe_connected.load(Ordering::Relaxed)
+            && self.request_connected.load(Ordering::Relaxed)
+    }
+
+    pub fn workers_alive(&self) -> usize {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .iter()
+            .filter(|w| w.alive.load(Ordering::Relaxed))
+            .count()
+    }
+
+    // threads are alive if they have recently shown progress, a worker
+    // blocked waiting for a job is alive as long as its thread is
+    pub fn healthy(&self, limit: Duration) -> bool {
+        if self.poller.age() > limit || self.sender.age() > limit {
+            log::warn!("C{}: poller or sender not responding", self.number);
+            return false;
+        }
+        let workers = self.workers.lock().unwrap();
+        for w in workers.iter() {
+            if !w.alive.load(Ordering::Relaxed) {
+                log::warn!("C{}: W{}: has stopped", self.number, w.number);
+                return false;
+            }
+            if !w.waiting.load(Ordering::Relaxed) && w.heartbeat.age() > limit {
+                log::warn!("C{}: W{}: no hash progress", self.number, w.number);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Worker {
+    pub fn hashed(&self, connection: &Connection) {
+        self.hashes.fetch_add(1, Ordering::Relaxed);
+        connection.hashes.fetch_add(1, Ordering::Relaxed);
+        self.heartbeat.beat();
+    }
+
+    pub fn set_waiting(&self, waiting: bool) {
+        self.waiting.store(waiting, Ordering::Relaxed);
+        self.heartbeat.beat();
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.0.store(now_millis(), Ordering::Relaxed);
+    }
+
+    pub fn age(&self) -> Duration {
+        match self.0.load(Ordering::Relaxed) {
+            0 => Duration::MAX,
+            last => Duration::from_millis(now_millis().saturating_sub(last)),
+        }
+    }
+}
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.alive.store(false, Ordering::Relaxed);
+    }
+}
+
+// from 1 so a beat is never 0
+fn now_millis() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64 + 1
+}
+
+// hash rate over the interval between successive samples
+#[derive(Debug)]
+pub struct Rate {
+    hashes: u64,
+    time: std::time::Instant,
+}
+
+impl Rate {
+    pub fn new(hashes: u64) -> Self {
+        Rate {
+            hashes,
+            time: std::time::Instant::now(),
+        }
+    }
+
+    pub fn sample(&mut self, hashes: u64) -> f64 {
+        let elapsed = self.time.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            hashes.saturating_sub(self.hashes) as f64 / elapsed
+        } else {
+            0.0
+        };
+        *self = Rate::new(hashes);
+        rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_healthy() {
+        let c = Connection::new(1);
+        assert!(!c.healthy(Duration::from_secs(1)));
+        c.poller.beat();
+        c.sender.beat();
+        let w = c.add_worker(1);
+        assert!(c.healthy(Duration::from_secs(1)));
+        assert_eq!(c.workers_alive(), 1);
+
+        // hashing with no progress
+        w.waiting.store(false, Ordering::Relaxed);
+        w.heartbeat.0.store(0, Ordering::Relaxed);
+        assert!(!c.healthy(Duration::from_secs(1)));
+
+        w.hashed(&c);
+        assert!(c.healthy(Duration::from_secs(1)));
+        assert_eq!(c.hashes.load(Ordering::Relaxed), 1);
+
+        drop(AliveGuard(w));
+        assert!(!c.healthy(Duration::from_secs(1)));
+        assert_eq!(c.workers_alive(), 0);
+
+        c.remove_worker(1);
+        assert!(c.healthy(Duration::from_secs(1)));
+    }
+}
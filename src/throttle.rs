@@ -0,0 +1,296 @@
+// throttle.rs
+
+// slow hashing down while the host is busy, short of memory or hot
+//
+// each check raises the throttle level one step while any reading is
+// over its threshold and lowers it one step once all are comfortably
+// below; depending on the mode a level removes that many tenths of the
+// active workers or makes each worker idle for that share of its time
+
+use simple_error::bail;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::config;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// at most 90% reduction, so some progress is always made
+const MAXIMUM_LEVEL: u32 = 9;
+
+// how often a duty pause checks for new work
+const DUTY_POLL: Duration = Duration::from_millis(250);
+
+// readings must fall below this fraction of a threshold to step down
+const HYSTERESIS: f64 = 0.9;
+
+#[derive(Debug, Default)]
+pub struct Throttle {
+    config: config::Throttle,
+    level: AtomicU32,
+}
+
+impl Throttle {
+    pub fn new(config: &config::Throttle) -> MyResult<Self> {
+        if config.mode != "duty" && config.mode != "workers" {
+            bail!("invalid throttle mode: {}", config.mode);
+        }
+        Ok(Throttle {
+            config: config.clone(),
+            level: AtomicU32::new(0),
+        })
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enable
+    }
+
+    // in workers mode: whether worker w of n should stand idle
+    pub fn parks(&self, w: u32, n: usize) -> bool {
+        if self.config.mode != "workers" {
+            return false;
+        }
+        let level = self.level() as usize;
+        let active = (n * (10 - level)).div_ceil(10).max(1);
+        w as usize > active
+    }
+
+    // in duty mode: idle after a hash so the worker is busy for only
+    // (10 - level) tenths of the time, ending early once arrived says
+    // there is new work
+    pub fn duty_pause(&self, hashing: Duration, mut arrived: impl FnMut() -> bool) {
+        if self.config.mode != "duty" {
+            return;
+        }
+        let level = self.level();
+        if level == 0 {
+            return;
+        }
+        let end = Instant::now() + hashing * level / (10 - level);
+        loop {
+            let left = end.saturating_duration_since(Instant::now());
+            if left.is_zero() || arrived() {
+                return;
+            }
+            std::thread::sleep(left.min(DUTY_POLL));
+        }
+    }
+
+    // current readings against their thresholds, a zero threshold or a
+    // missing file is not checked
+    fn readings(&self) -> Vec<(&'static str, f64, f64)> {
+        let c = &self.config;
+        let mut r = Vec::new();
+        if c.load > 0.0 {
+            if let Some(v) = read_loadavg(&c.loadavg_path) {
+                r.push(("load", v, c.load));
+            }
+        }
+        if c.memory_pressure > 0.0 {
+            if let Some(v) = read_pressure(&c.pressure_path) {
+                r.push(("memory_pressure", v, c.memory_pressure));
+            }
+        }
+        if c.temperature > 0.0 {
+            if let Some(v) = read_temperature(&c.thermal_path) {
+                r.push(("temperature", v, c.temperature));
+            }
+        }
+        r
+    }
+
+    fn check(&self) {
+        let readings = self.readings();
+        let level = self.level();
+        let next = next_level(level, &readings);
+        if next == level {
+            return;
+        }
+        self.level.store(next, Ordering::Relaxed);
+
+        let values: Vec<String> = readings
+            .iter()
+            .map(|(name, v, limit)| format!("{}: {:.2}/{}", name, v, limit))
+            .collect();
+        if next > level {
+            log::warn!(throttle = next * 10; "throttle: {}%  {}", next * 10, values.join("  "));
+        } else {
+            log::info!(throttle = next * 10; "throttle: {}%  {}", next * 10, values.join("  "));
+        }
+    }
+}
+
+fn next_level(level: u32, readings: &[(&str, f64, f64)]) -> u32 {
+    if readings.iter().any(|(_, v, limit)| v > limit) {
+        (level + 1).min(MAXIMUM_LEVEL)
+    } else if readings.iter().all(|(_, v, limit)| *v < limit * HYSTERESIS) {
+        level.saturating_sub(1)
+    } else {
+        level
+    }
+}
+
+pub fn spawn(throttle: Arc<Throttle>) {
+    let c = &throttle.config;
+    if !c.enable {
+        return;
+    }
+    if c.load > 0.0 && read_loadavg(&c.loadavg_path).is_none() {
+        log::warn!("throttle: cannot read load from: {}", c.loadavg_path);
+    }
+    if c.memory_pressure > 0.0 && read_pressure(&c.pressure_path).is_none() {
+        log::warn!(
+            "throttle: cannot read memory pressure from: {}",
+            c.pressure_path
+        );
+    }
+    if c.temperature > 0.0 && read_temperature(&c.thermal_path).is_none() {
+        log::warn!("throttle: cannot read temperature from: {}", c.thermal_path);
+    }
+
+    let interval = Duration::from_secs(c.interval.max(1));
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        throttle.check();
+    });
+}
+
+// 1 minute load average: "0.52 0.58 0.59 1/467 12345"
+fn read_loadavg(path: &str) -> Option<f64> {
+    let s = std::fs::read_to_string(path).ok()?;
+    s.split_whitespace().next()?.parse().ok()
+}
+
+// PSI percentage of time some tasks stalled on memory over 10 seconds:
+//   some avg10=1.25 avg60=0.40 avg300=0.10 total=123456
+fn read_pressure(path: &str) -> Option<f64> {
+    let s = std::fs::read_to_string(path).ok()?;
+    let line = s.lines().find(|l| l.starts_with("some "))?;
+    let avg10 = line
+        .split_whitespace()
+        .find_map(|f| f.strip_prefix("avg10="))?;
+    avg10.parse().ok()
+}
+
+// hottest thermal zone in °C, the kernel reports millidegrees
+fn read_temperature(directory: &str) -> Option<f64> {
+    let mut hottest: Option<f64> = None;
+    for entry in std::fs::read_dir(directory).ok()?.flatten() {
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with("thermal_zone")
+        {
+            continue;
+        }
+        let temp = entry.path().join("temp");
+        if let Ok(s) = std::fs::read_to_string(temp) {
+            if let Ok(m) = s.trim().parse::<f64>() {
+                let t = m / 1000.0;
+                hottest = Some(hottest.map_or(t, |h| h.max(t)));
+            }
+        }
+    }
+    hottest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duty_pause() {
+        let t = Throttle::new(&config::Throttle {
+            mode: "duty".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        t.level.store(MAXIMUM_LEVEL, Ordering::Relaxed);
+
+        // a 90 second pause ends on the second check for work
+        let start = Instant::now();
+        let mut checks = 0;
+        t.duty_pause(Duration::from_secs(10), || {
+            checks += 1;
+            checks == 2
+        });
+        assert_eq!(checks, 2);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_readings() {
+        let dir = std::env::temp_dir().join(format!("mt-recorder-throttle-{}", std::process::id()));
+        let thermal = dir.join("thermal");
+        std::fs::create_dir_all(thermal.join("thermal_zone0")).unwrap();
+        std::fs::create_dir_all(thermal.join("thermal_zone1")).unwrap();
+        std::fs::create_dir_all(thermal.join("cooling_device0")).unwrap();
+        std::fs::write(thermal.join("thermal_zone0/temp"), "45000\n").unwrap();
+        std::fs::write(thermal.join("thermal_zone1/temp"), "81500\n").unwrap();
+        std::fs::write(thermal.join("cooling_device0/temp"), "99000\n").unwrap();
+        std::fs::write(dir.join("loadavg"), "3.50 2.00 1.00 2/300 4242\n").unwrap();
+        std::fs::write(
+            dir.join("memory"),
+            "some avg10=12.50 avg60=3.00 avg300=1.00 total=999\n\
+             full avg10=2.00 avg60=1.00 avg300=0.50 total=111\n",
+        )
+        .unwrap();
+
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let throttle = Throttle::new(&config::Throttle {
+            enable: true,
+            mode: "workers".to_string(),
+            interval: 1,
+            load: 4.0,
+            memory_pressure: 10.0,
+            temperature: 0.0,
+            loadavg_path: path("loadavg"),
+            pressure_path: path("memory"),
+            thermal_path: path("thermal"),
+        })
+        .unwrap();
+        assert_eq!(read_temperature(&path("thermal")), Some(81.5));
+        assert_eq!(read_temperature(&path("missing")), None);
+
+        // temperature is not checked, memory pressure is over
+        assert_eq!(
+            throttle.readings(),
+            vec![("load", 3.5, 4.0), ("memory_pressure", 12.5, 10.0)]
+        );
+        throttle.check();
+        throttle.check();
+        assert_eq!(throttle.level(), 2);
+
+        // 8 workers at 80%
+        assert!(!throttle.parks(7, 8));
+        assert!(throttle.parks(8, 8));
+        assert!(!throttle.parks(1, 1));
+
+        // between hysteresis and threshold holds, below steps down
+        std::fs::write(
+            dir.join("memory"),
+            "some avg10=9.50 avg60=3.00 avg300=1.00 total=999\n",
+        )
+        .unwrap();
+        throttle.check();
+        assert_eq!(throttle.level(), 2);
+        std::fs::write(
+            dir.join("memory"),
+            "some avg10=1.00 avg60=3.00 avg300=1.00 total=999\n",
+        )
+        .unwrap();
+        throttle.check();
+        assert_eq!(throttle.level(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let over = [("load", 9.0, 1.0)];
+        assert_eq!(next_level(MAXIMUM_LEVEL, &over), MAXIMUM_LEVEL);
+        assert_eq!(next_level(0, &[]), 0);
+    }
+}
@@ -0,0 +1,153 @@
+// verify.rs
+
+// offline tools: check a header and nonce to reproduce a rejected
+// submission, the input being a job as published by bitmarkd or the hex
+// of a packed header with or without its nonce; and decode a header
+
+use bytes::BufMut;
+use simple_error::bail;
+use std::io::Read;
+
+use super::block;
+use super::responder;
+
+type MyResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// input is "-" for stdin, a file name or the text itself
+fn read_input(input: &str) -> MyResult<Vec<u8>> {
+    if input == "-" {
+        let mut b = Vec::new();
+        std::io::stdin().read_to_end(&mut b)?;
+        Ok(b)
+    } else if std::path::Path::new(input).is_file() {
+        Ok(std::fs::read(input)?)
+    } else {
+        Ok(input.as_bytes().to_vec())
+    }
+}
+
+// a nonce given as 16 hex digits replaces the one in the input
+pub fn run(input: &str, nonce: Option<&str>, params: &block::Params) -> MyResult<String> {
+    let text = String::from_utf8(read_input(input)?)?;
+    let nonce = match nonce {
+        Some(n) => match u64::from_str_radix(n.trim_start_matches("0x"), 16) {
+            Ok(n) => Some(n),
+            Err(_) => bail!("invalid nonce: {}", n),
+        },
+        None => None,
+    };
+    let packed = pack(text.trim(), nonce)?;
+    Ok(report(&packed, params))
+}
+
+// the 100 byte header with nonce
+fn pack(text: &str, nonce: Option<u64>) -> MyResult<Vec<u8>> {
+    let (header, header_nonce) = if text.starts_with('{') {
+        let job: responder::Job = serde_json::from_str(text)?;
+        let n = u64::from_le_bytes(job.header.nonce);
+        (bytes::Bytes::from(job.header).to_vec(), Some(n))
+    } else {
+        let mut b = match hex::decode(text) {
+            Ok(b) => b,
+            Err(e) => bail!("invalid hex header: {}", e),
+        };
+        match b.len() {
+            92 => (b, None),
+            100 => {
+                let n = u64::from_le_bytes(b[92..].try_into().unwrap());
+                b.truncate(92);
+                (b, Some(n))
+            }
+            n => bail!("header is: {} bytes, expected 92 or 100", n),
+        }
+    };
+    let nonce = match nonce.or(header_nonce) {
+        Some(n) => n,
+        None => bail!("a 92 byte header needs a nonce"),
+    };
+    let mut buf = bytes::BytesMut::with_capacity(100);
+    buf.put_slice(&header);
+    buf.put_u64_le(nonce);
+    Ok(buf.to_vec())
+}
+
+// hex text or the raw 92 or 100 bytes
+pub fn inspect(input: &str) -> MyResult<String> {
+    let b = read_input(input)?;
+    let header = match std::str::from_utf8(&b) {
+        Ok(s) if s.trim().bytes().all(|c| c.is_ascii_hexdigit()) => match hex::decode(s.trim()) {
+            Ok(b) => block::Header::parse(&b)?,
+            Err(e) => bail!("invalid hex header: {}", e),
+        },
+        _ => block::Header::parse(&b)?,
+    };
+    Ok(header.to_string())
+}
+
+// parameters other than bitmarkd's are shown
+fn report(packed: &[u8], params: &block::Params) -> String {
+    let difficulty: [u8; 8] = packed[84..92].try_into().unwrap();
+    let nonce = u64::from_le_bytes(packed[92..100].try_into().unwrap());
+    let digest = block::digest(packed, params);
+    let target = block::difficulty_target(&difficulty);
+    let mut s = String::new();
+    if *params != block::LIVE {
+        s += &format!(
+            "argon2:     {:?} {:?}  memory: {} KiB  iterations: {}  lanes: {}\n",
+            params.variant, params.version, params.memory, params.iterations, params.lanes
+        );
+    }
+    s + &format!(
+        "packed:     {}\n\
+         block:      {}\n\
+         nonce:      {}\n\
+         digest:     {}\n\
+         difficulty: {}\n\
+         target:     {}\n\
+         satisfies:  {}\n",
+        hex::encode(packed),
+        u64::from_le_bytes(packed[4..12].try_into().unwrap()),
+        block::nonce_hex(nonce),
+        hex::encode(&digest),
+        hex::encode(difficulty),
+        hex::encode(target),
+        if block::satisfies(&digest, &target) {
+            "yes"
+        } else {
+            "no"
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack() {
+        let header = "0100010001000000000000000000000000000000000000000000000000000000000000000000000000000000638c159c1f113f70a9866d9a9e52e9efe9b9920848ad1df34851be8a562a998db79a805600000000ffffffffffffff00";
+        let job = format!(
+            r#"{{"job":"01","header":{{"version":1,"transactionCount":1,"number":"1",
+            "previousBlock":"{}","merkleRoot":"638c159c1f113f70a9866d9a9e52e9efe9b9920848ad1df34851be8a562a998d",
+            "timestamp":"1451268791","difficulty":"ffffffffffffff00","nonce":"115a38bf3a909fe1"}},
+            "txZero":"","txIds":[]}}"#,
+            "00".repeat(32)
+        );
+
+        let from_job = pack(&job, None).unwrap();
+        assert_eq!(hex::encode(&from_job[..92]), header);
+        assert_eq!(
+            from_job[92..],
+            [0x11, 0x5a, 0x38, 0xbf, 0x3a, 0x90, 0x9f, 0xe1]
+        );
+
+        // same with the hex header and the nonce as logged
+        assert_eq!(pack(header, Some(0xe19f903abf385a11)).unwrap(), from_job);
+        let full = hex::encode(&from_job);
+        assert_eq!(pack(&full, None).unwrap(), from_job);
+        assert_eq!(pack(&full, Some(1)).unwrap()[92], 1);
+
+        assert!(pack(header, None).is_err());
+        assert!(pack("0011", None).is_err());
+    }
+}
@@ -2,44 +2,305 @@
 
 use bytes::BufMut;
 use log;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use super::block;
+use super::config;
+use super::hooks;
+use super::reference;
+use super::resources;
 use super::responder;
+use super::schedule;
+use super::selftest;
+use super::statistics;
+use super::throttle;
+
+type MyResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 // to limit the hashing if no more jobs (2 minutes)
 const MAXIMUM_HASH_SECONDS: u64 = 120;
 
-pub struct Result {
-    pub join_handles: Vec<std::thread::JoinHandle<()>>,
-    pub channel_txs: Vec<spmc::Sender<(bytes::Bytes, u64, String)>>,
+// how often a paused worker checks to resume
+const PAUSE_POLL: Duration = Duration::from_millis(250);
+
+// how often the supervisor checks the workers
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(5);
+
+// a backend that fails its self test this often is not going to
+// pass, its workers are left stopped
+const SELF_TEST_FAILURES: u64 = 3;
+
+// nonce range of each worker
+const NONCE_STRIDE: u64 = 0x100000000;
+
+// block header without nonce, starting nonce and job id
+pub type Work = (bytes::Bytes, u64, String);
+
+// how the workers of one connection hash, shared by all of them
+#[derive(Debug, Default)]
+pub struct Settings {
+    pub schedule: schedule::Schedule,
+    pub throttle: Arc<throttle::Throttle>,
+    pub verify_digest: bool,
+    pub self_test_interval: Option<Duration>,
+    pub params: block::Params,
+    // consecutive nonces hashed together, 0 is the same as 1
+    pub interleave: u32,
+    // a found digest must meet this, without it any digest with a zero
+    // top byte is submitted for bitmarkd to judge
+    pub target: Option<[u8; 32]>,
+}
+
+impl Settings {
+    // the digest parameters of a connection, a change from bitmarkd's
+    // is only for private chains so is worth a warning
+    pub fn set_params(&mut self, set: i64, argon2: &config::Argon2) -> MyResult<()> {
+        self.params = block::Params::new(argon2)?;
+        if self.params != block::LIVE {
+            log::warn!(
+                "C{}: argon2 parameters differ from bitmarkd: {:?}",
+                set,
+                argon2
+            );
+        }
+        if self.verify_digest && !reference::supports(&self.params) {
+            log::warn!(
+                "C{}: verify_digest needs argon2d version 0x13, not checking",
+                set
+            );
+            self.verify_digest = false;
+        }
+        Ok(())
+    }
+}
+
+// the workers of one connection, these can be added or removed while
+// running and new workers start on the most recent job
+pub struct Pool {
+    set: i64,
+    tx: std::sync::mpsc::Sender<responder::Response>,
+    hooks: Arc<hooks::Hooks>,
+    stats: Arc<statistics::Connection>,
+    settings: Arc<Settings>,
+    channel_txs: Vec<spmc::Sender<Work>>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+    last: Option<Work>,
+    // replaced stalled threads, joined if they ever end
+    stalled: Vec<std::thread::JoinHandle<()>>,
+    // nonce ranges given to replacements, counted down from the top
+    // so they never overlap a range of the current job
+    spare: u64,
+    // workers are no longer restarted after repeated self test failures
+    failed: bool,
 }
 
 pub fn create_workers(
     set: i64,
     workers: u32,
     tx: std::sync::mpsc::Sender<responder::Response>,
-) -> Result {
-    let hash_count = std::sync::atomic::AtomicU64::new(0);
-    let arc_hash_count = std::sync::Arc::new(hash_count);
-
-    let mut result = Result {
-        join_handles: Vec::new(),
+    hooks: Arc<hooks::Hooks>,
+    stats: Arc<statistics::Connection>,
+    settings: Settings,
+) -> Pool {
+    let mut pool = Pool {
+        set,
+        tx,
+        hooks,
+        stats,
+        settings: Arc::new(settings),
         channel_txs: Vec::new(),
+        threads: Vec::new(),
+        last: None,
+        stalled: Vec::new(),
+        spare: 0,
+        failed: false,
     };
 
     log::debug!("C{}: creating: {} workers", set, workers);
-
     for w in 1..=workers {
-        let (subscribe_tx, subscribe_rx) = spmc::channel::<(bytes::Bytes, u64, String)>();
+        pool.spawn(w);
+    }
 
-        result.channel_txs.push(subscribe_tx);
-        let tx = tx.clone();
-        let hash_count = arc_hash_count.clone();
-        result.join_handles.push(std::thread::spawn(move || {
+    pool
+}
+
+impl Pool {
+    pub fn len(&self) -> usize {
+        self.channel_txs.len()
+    }
+
+    // bytes of Argon2 memory each worker needs
+    pub fn worker_memory(&self) -> u64 {
+        resources::batch_memory(self.settings.params.memory, self.settings.interleave)
+    }
+
+    // give each worker its own range of nonces
+    pub fn send(&mut self, blk: bytes::Bytes, nonce: u64, job: &str) -> MyResult<()> {
+        let mut nnn = nonce;
+        for (i, tx) in self.channel_txs.iter_mut().enumerate() {
+            log::info!(
+                connection = self.set,
+                worker = i + 1,
+                job = job,
+                nonce:% = block::nonce_hex(nnn);
+                "C{}: W{}: nonce: {}",
+                self.set,
+                i + 1,
+                block::nonce_hex(nnn)
+            );
+            tx.send((blk.clone(), nnn, job.to_string()))?;
+            nnn = nnn.wrapping_add(NONCE_STRIDE);
+        }
+        self.last = Some((blk, nonce, job.to_string()));
+        self.spare = 0;
+        Ok(())
+    }
+
+    // dropping the channel stops a worker after its current hash
+    pub fn resize(&mut self, workers: u32) -> MyResult<()> {
+        let current = self.channel_txs.len() as u32;
+        log::warn!("C{}: workers: {} ⇒ {}", self.set, current, workers);
+
+        for w in (workers + 1..=current).rev() {
+            self.channel_txs.pop();
+            self.threads.pop();
+            self.stats.remove_worker(w);
+        }
+        for w in current + 1..=workers {
+            self.spawn(w);
+            self.resend(w, w as u64 - 1)?;
+        }
+        Ok(())
+    }
+
+    // replace workers whose thread has ended or that have made no hash
+    // progress within the stall limit; a stalled thread cannot be
+    // stopped but its channel is dropped so it ends if it ever continues,
+    // and its replacement hashes a range the stalled one does not
+    pub fn supervise(&mut self, stall: Option<Duration>) -> MyResult<()> {
+        let (ended, stalled): (Vec<_>, Vec<_>) = std::mem::take(&mut self.stalled)
+            .into_iter()
+            .partition(|t| t.is_finished());
+        for t in ended {
+            let _ = t.join();
+        }
+        self.stalled = stalled;
+
+        // a whole batch of interleaved digests shows progress at once
+        let stall = stall.map(|limit| limit * self.settings.interleave.max(1));
+        for i in 0..self.threads.len() {
+            let w = i as u32 + 1;
+            let old = self.stats.worker(w);
+            let stalled = match (&old, stall) {
+                (Some(old), Some(limit)) => {
+                    !old.waiting.load(Ordering::Relaxed) && old.heartbeat.age() > limit
+                }
+                _ => false,
+            };
+            if !stalled && !self.threads[i].is_finished() {
+                continue;
+            }
+
+            // stopped workers make the connection unhealthy, so the
+            // watchdog is no longer fed
+            if !stalled
+                && self.stats.self_test_failures.load(Ordering::Relaxed) >= SELF_TEST_FAILURES
+            {
+                if !self.failed {
+                    log::error!(
+                        connection = self.set;
+                        "C{}: self test failed: {} times, not restarting workers",
+                        self.set,
+                        SELF_TEST_FAILURES
+                    );
+                    self.failed = true;
+                }
+                continue;
+            }
+
+            self.stats.remove_worker(w);
+            let (tx, thread) = self.start(w);
+            let cause = if stalled {
+                self.stalled
+                    .push(std::mem::replace(&mut self.threads[i], thread));
+                format!("no hash progress for: {:?}", stall.unwrap_or_default())
+            } else {
+                match std::mem::replace(&mut self.threads[i], thread).join() {
+                    Ok(()) => "thread ended".to_string(),
+                    Err(e) => match e.downcast_ref::<&str>() {
+                        Some(s) => format!("panic: {}", s),
+                        None => match e.downcast_ref::<String>() {
+                            Some(s) => format!("panic: {}", s),
+                            None => "panic".to_string(),
+                        },
+                    },
+                }
+            };
+            self.channel_txs[i] = tx;
+
+            let restarts = old.map_or(0, |o| o.restarts.load(Ordering::Relaxed)) + 1;
+            if let Some(new) = self.stats.worker(w) {
+                new.restarts.store(restarts, Ordering::Relaxed);
+            }
+            self.stats.restarts.fetch_add(1, Ordering::Relaxed);
+            log::error!(
+                connection = self.set,
+                worker = w,
+                restarts = restarts;
+                "C{}: W{}: {}  restart: {}",
+                self.set,
+                w,
+                cause,
+                restarts
+            );
+            let range = if stalled {
+                self.spare += 1;
+                u32::MAX as u64 + 1 - self.spare
+            } else {
+                w as u64 - 1
+            };
+            self.resend(w, range)?;
+        }
+        Ok(())
+    }
+
+    // give a new worker a range of the most recent job
+    fn resend(&mut self, w: u32, range: u64) -> MyResult<()> {
+        if let Some((blk, nonce, job)) = &self.last {
+            let nonce = nonce.wrapping_add(NONCE_STRIDE.wrapping_mul(range));
+            self.channel_txs[w as usize - 1].send((blk.clone(), nonce, job.clone()))?;
+        }
+        Ok(())
+    }
+
+    fn spawn(&mut self, w: u32) {
+        let (tx, thread) = self.start(w);
+        self.channel_txs.push(tx);
+        self.threads.push(thread);
+    }
+
+    fn start(&self, w: u32) -> (spmc::Sender<Work>, std::thread::JoinHandle<()>) {
+        let (subscribe_tx, subscribe_rx) = spmc::channel::<Work>();
+
+        let set = self.set;
+        let tx = self.tx.clone();
+        let hooks = self.hooks.clone();
+        let stats = self.stats.clone();
+        let settings = self.settings.clone();
+        let worker_stats = stats.add_worker(w);
+        let thread = std::thread::spawn(move || {
+            let _alive = statistics::AliveGuard(worker_stats.clone());
+            let mut tested: Option<Instant> = None;
             'waiting: loop {
                 log::debug!("C{}: W{}: waiting..", set, w);
-                let (mut blk, mut nonce, mut job) = subscribe_rx.recv().unwrap();
+                worker_stats.set_waiting(true);
+                let (mut blk, mut nonce, mut job) = match subscribe_rx.recv() {
+                    Ok(work) => work,
+                    Err(_) => break 'waiting,
+                };
+                worker_stats.set_waiting(false);
 
                 // let mut hex_blk = String::new();
                 // let _res = blk.write_hex(&mut hex_blk);
@@ -52,42 +313,158 @@ pub fn create_workers(
                     log::debug!("C{}: W{}: start hashing", set, w);
                     let mut i = 0;
                     let start = Instant::now();
-                    let end = start + Duration::new(MAXIMUM_HASH_SECONDS, 0);
+                    let mut end = start + Duration::new(MAXIMUM_HASH_SECONDS, 0);
                     let mut wait = false;
 
                     'hashing: loop {
-                        let mut buf = bytes::BytesMut::with_capacity(100);
-                        buf.put_slice(&blk);
-                        buf.put_u64_le(nonce);
-                        assert_eq!(buf.len(), 100);
-
-                        let hg = block::block_digest(&buf);
-                        i += 1;
-
-                        // check little_endian MSB
-                        if hg[31] == 0 {
-                            log::trace!(
-                                "C{}: W{}:  hg: {:02x?}  nonce: {:016x}",
-                                set,
-                                w,
-                                hg,
-                                nonce
-                            );
-
-                            let response = responder::Response {
-                                request: "block.nonce".to_string(),
-                                job: job.clone(),
-                                packed: nonce.to_le_bytes().to_vec(),
+                        // hold the current job while paused, keeping
+                        // only the latest job that arrives meanwhile
+                        if held(set, w, &stats, &settings) {
+                            log::info!("C{}: W{}: paused", set, w);
+                            worker_stats.set_waiting(true);
+                            let paused = Instant::now();
+                            let mut renewed = false;
+                            while held(set, w, &stats, &settings) {
+                                match subscribe_rx.try_recv() {
+                                    Ok((b, n, j)) => {
+                                        blk = b;
+                                        nonce = n;
+                                        job = j;
+                                        renewed = true;
+                                    }
+                                    Err(std::sync::mpsc::TryRecvError::Empty) => {
+                                        std::thread::sleep(PAUSE_POLL)
+                                    }
+                                    Err(_) => break 'waiting,
+                                }
+                            }
+                            end += paused.elapsed();
+                            worker_stats.set_waiting(false);
+                            log::info!("C{}: W{}: resumed", set, w);
+                            if renewed {
+                                break 'hashing;
+                            }
+                        }
+
+                        // a worker checks itself before its first hash
+                        // and then at each interval
+                        if let Some(interval) = settings.self_test_interval {
+                            if tested.is_none_or(|t| t.elapsed() >= interval) {
+                                if let Err(e) =
+                                    selftest::check_params(&settings.params, settings.interleave)
+                                {
+                                    stats.self_test_failures.fetch_add(1, Ordering::Relaxed);
+                                    log::error!(
+                                        connection = set,
+                                        worker = w;
+                                        "C{}: W{}: {}, stopping",
+                                        set,
+                                        w,
+                                        e
+                                    );
+                                    break 'waiting;
+                                }
+                                log::debug!("C{}: W{}: self test: passed", set, w);
+                                tested = Some(Instant::now());
+                            }
+                        }
+
+                        let count = settings.interleave.max(1) as u64;
+                        let batch: Vec<bytes::BytesMut> = (0..count)
+                            .map(|k| {
+                                let mut buf = bytes::BytesMut::with_capacity(100);
+                                buf.put_slice(&blk);
+                                buf.put_u64_le(nonce + k);
+                                assert_eq!(buf.len(), 100);
+                                buf
+                            })
+                            .collect();
+                        let data: Vec<&[u8]> = batch.iter().map(|b| &b[..]).collect();
+
+                        let hashing = Instant::now();
+                        let digests = block::digests(&data, &settings.params);
+                        let hashing = hashing.elapsed();
+                        i += count;
+                        for _ in 0..count {
+                            worker_stats.hashed(&stats);
+                        }
+
+                        for (k, (buf, hg)) in batch.iter().zip(digests).enumerate() {
+                            let nonce = nonce + k as u64;
+                            // check little_endian MSB
+                            let found = match &settings.target {
+                                Some(target) => block::satisfies(&hg, target),
+                                None => hg[31] == 0,
                             };
-                            tx.send(response).unwrap();
+                            if found {
+                                log::trace!(
+                                    connection = set,
+                                    worker = w,
+                                    job = job.as_str(),
+                                    nonce:% = block::nonce_hex(nonce);
+                                    "C{}: W{}:  hg: {:02x?}  nonce: {}",
+                                    set,
+                                    w,
+                                    hg,
+                                    block::nonce_hex(nonce)
+                                );
+
+                                let verified = !settings.verify_digest
+                                    || verify(set, w, buf, &hg, &settings.params, &stats);
+                                let accept = verified
+                                    && hooks.on_nonce_found(|t| {
+                                        t.set("connection", set)?;
+                                        t.set("worker", w)?;
+                                        t.set("job", job.as_str())?;
+                                        t.set(
+                                            "block",
+                                            u64::from_le_bytes(blk[4..12].try_into().unwrap()),
+                                        )?;
+                                        t.set("nonce", block::nonce_hex(nonce))?;
+                                        t.set("digest", hex::encode(&hg))
+                                    });
+
+                                if accept {
+                                    let response = responder::Response {
+                                        request: "block.nonce".to_string(),
+                                        job: job.clone(),
+                                        packed: nonce.to_le_bytes().to_vec(),
+                                        header: blk.clone(),
+                                        digest: hg.clone(),
+                                        worker: w,
+                                    };
+                                    tx.send(response).unwrap();
+                                } else if verified {
+                                    log::info!(
+                                        "C{}: W{}: nonce: {}  rejected by on_nonce_found hook",
+                                        set,
+                                        w,
+                                        block::nonce_hex(nonce)
+                                    );
+                                }
+                            }
                         }
                         if Instant::now() > end {
                             wait = true;
                             break 'hashing;
                         }
-                        nonce += 1;
+                        nonce += count;
 
-                        match subscribe_rx.try_recv() {
+                        // a job that arrives during a duty pause ends it
+                        let mut next = None;
+                        worker_stats.set_waiting(true);
+                        settings
+                            .throttle
+                            .duty_pause(hashing, || match subscribe_rx.try_recv() {
+                                Err(std::sync::mpsc::TryRecvError::Empty) => false,
+                                received => {
+                                    next = Some(received);
+                                    true
+                                }
+                            });
+                        worker_stats.set_waiting(false);
+
+                        match next.unwrap_or_else(|| subscribe_rx.try_recv()) {
                             Ok((b, n, j)) => {
                                 blk = b;
                                 nonce = n;
@@ -98,11 +475,16 @@ pub fn create_workers(
                             Err(_) => break 'waiting,
                         };
                     }
-                    hash_count.fetch_add(i, std::sync::atomic::Ordering::SeqCst);
                     let duration = start.elapsed();
                     let elapsed = duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9;
                     let average = i as f64 / elapsed;
                     log::info!(
+                        connection = set,
+                        worker = w,
+                        job = job.as_str(),
+                        hashes = i,
+                        elapsed = elapsed,
+                        hash_rate = average;
                         "C{}: W{}:  hashes: {}  in: {:6.2}  average: {:7.3}",
                         set,
                         w,
@@ -115,9 +497,147 @@ pub fn create_workers(
                     }
                 }
             }
-            log::debug!("C{}: worker: {}  failed", set, w);
-        }));
+            log::debug!("C{}: worker: {}  stopped", set, w);
+        });
+        (subscribe_tx, thread)
+    }
+}
+
+// check the workers of a connection every few seconds
+pub fn supervise(set: i64, pool: Arc<std::sync::Mutex<Pool>>, stall_seconds: u64) {
+    let stall = match stall_seconds {
+        0 => None,
+        s => Some(Duration::from_secs(s)),
+    };
+    std::thread::spawn(move || loop {
+        std::thread::sleep(SUPERVISE_INTERVAL);
+        if let Err(e) = pool.lock().unwrap().supervise(stall) {
+            log::error!("C{}: supervise error: {}", set, e);
+        }
+    });
+}
+
+// paused from the control socket, outside the schedule or parked by
+// the throttle, a change of schedule state is logged by whichever
+// worker sees it first
+fn held(set: i64, w: u32, stats: &statistics::Connection, settings: &Settings) -> bool {
+    let off = !settings
+        .schedule
+        .active(&chrono::Local::now().naive_local());
+    if stats.off_schedule.swap(off, Ordering::Relaxed) != off {
+        if off {
+            log::warn!(connection = set; "C{}: outside schedule, pausing", set);
+        } else {
+            log::warn!(connection = set; "C{}: inside schedule, resuming", set);
+        }
+    }
+    off || stats.paused.load(Ordering::Relaxed)
+        || settings
+            .throttle
+            .parks(w, stats.workers.lock().unwrap().len())
+}
+
+// recompute a found digest with the reference implementation, a
+// difference means the hashing cannot be trusted on this host
+fn verify(
+    set: i64,
+    w: u32,
+    data: &[u8],
+    digest: &[u8],
+    params: &block::Params,
+    stats: &statistics::Connection,
+) -> bool {
+    let expected = reference::digest(data, params);
+    if expected == digest {
+        return true;
+    }
+    stats.hardware_errors.fetch_add(1, Ordering::Relaxed);
+    log::error!(
+        connection = set,
+        worker = w;
+        "C{}: W{}: hardware error: digest: {}  expected: {}  nonce dropped",
+        set,
+        w,
+        hex::encode(digest),
+        hex::encode(&expected)
+    );
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resize() {
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let stats = Arc::new(statistics::Connection::new(1));
+        let mut pool = create_workers(1, 2, tx, Arc::default(), stats.clone(), Settings::default());
+        assert_eq!(pool.len(), 2);
+
+        pool.resize(3).unwrap();
+        assert_eq!(pool.len(), 3);
+        assert_eq!(stats.workers.lock().unwrap().len(), 3);
+
+        // idle workers end as soon as their channel is dropped
+        pool.resize(1).unwrap();
+        assert_eq!(pool.len(), 1);
+        let numbers: Vec<u32> = stats
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|w| w.number)
+            .collect();
+        assert_eq!(numbers, vec![1]);
     }
 
-    result
+    #[test]
+    fn test_supervise() {
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let stats = Arc::new(statistics::Connection::new(1));
+        let mut pool = create_workers(1, 2, tx, Arc::default(), stats.clone(), Settings::default());
+        pool.supervise(Some(Duration::from_secs(60))).unwrap();
+        assert_eq!(stats.restarts.load(Ordering::Relaxed), 0);
+
+        // a worker thread that has died
+        pool.threads[1] = std::thread::spawn(|| panic!("test"));
+        while !pool.threads[1].is_finished() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        pool.supervise(None).unwrap();
+        assert_eq!(stats.restarts.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.worker(2).unwrap().restarts.load(Ordering::Relaxed), 1);
+        assert!(!pool.threads[1].is_finished());
+
+        // a worker stuck hashing
+        stats
+            .worker(1)
+            .unwrap()
+            .waiting
+            .store(false, Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(20));
+        pool.supervise(Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(stats.restarts.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.workers.lock().unwrap().len(), 2);
+        assert!(stats.worker(1).unwrap().waiting.load(Ordering::Relaxed));
+        assert_eq!(pool.spare, 1);
+
+        // the replaced thread ends once it sees its channel dropped
+        while !pool.stalled[0].is_finished() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        pool.supervise(None).unwrap();
+        assert!(pool.stalled.is_empty());
+
+        // no restarts once the self test keeps failing
+        stats.self_test_failures.store(3, Ordering::Relaxed);
+        pool.threads[0] = std::thread::spawn(|| {});
+        while !pool.threads[0].is_finished() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        pool.supervise(None).unwrap();
+        assert_eq!(stats.restarts.load(Ordering::Relaxed), 2);
+        assert!(pool.failed);
+    }
 }